@@ -0,0 +1,58 @@
+//! Records details of the build, exposed by the exporter's info metric
+//!
+//! Each of them is left unset when it can't be determined, e.g. when building outside of a git checkout.
+
+use std::env;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    if let Some(git_commit) = command_output("git", &["rev-parse", "--short=12", "HEAD"]) {
+        println!("cargo:rustc-env=GIT_COMMIT={}", git_commit);
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    if let Some(build_date) = build_date() {
+        println!("cargo:rustc-env=BUILD_DATE={}", build_date);
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    if let Some(rust_version) = command_output(&rustc, &["--version"]) {
+        println!("cargo:rustc-env=RUST_VERSION={}", rust_version);
+    }
+}
+
+/// Returns the trimmed standard output of the command, if it succeeded
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    let output = String::from_utf8(output.stdout)
+        .ok()
+        .filter(|_| output.status.success())?;
+    Some(output.trim().to_string()).filter(|output| !output.is_empty())
+}
+
+/// Formats the build's day as `YYYY-MM-DD`, honoring `SOURCE_DATE_EPOCH` for reproducible builds
+fn build_date() -> Option<String> {
+    let timestamp = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse::<u64>().ok()?,
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs(),
+    };
+    // Converts days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (timestamp / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    Some(format!("{:04}-{:02}-{:02}", year, month, day))
+}
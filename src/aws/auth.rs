@@ -1,7 +1,75 @@
-use aws_config::meta::credentials::LazyCachingCredentialsProvider;
+use aws_config::ecs::EcsCredentialsProvider;
+use aws_config::environment::EnvironmentVariableCredentialsProvider;
+use aws_config::meta::credentials::{CredentialsProviderChain, LazyCachingCredentialsProvider};
+use aws_config::profile::ProfileFileCredentialsProvider;
+use aws_config::provider_config::ProviderConfig;
 use aws_config::sts::AssumeRoleProvider;
-use aws_types::credentials::SharedCredentialsProvider;
+use aws_config::web_identity_token::{StaticConfiguration, WebIdentityTokenCredentialsProvider};
+use aws_sdk_sts::Endpoint;
+use aws_types::credentials::{
+    future, Credentials, CredentialsError, ProvideCredentials, SharedCredentialsProvider,
+};
 use aws_types::region::Region;
+use std::time::{Duration, UNIX_EPOCH};
+use warp::http::Uri;
+
+const DEFAULT_SESSION_NAME: &str = "aws-ecs-exporter";
+
+/// Returns a provider exchanging the web identity token for credentials of the role
+///
+/// The token file is read again on each refresh, as it's rotated by whatever mounts it.
+pub fn get_web_identity_provider(
+    token_file: &str,
+    role: &str,
+    session_name: Option<&str>,
+    region: Region,
+) -> SharedCredentialsProvider {
+    let web_identity_provider = WebIdentityTokenCredentialsProvider::builder()
+        .configure(&ProviderConfig::without_region().with_region(Some(region)))
+        .static_configuration(StaticConfiguration {
+            web_identity_token_file: token_file.into(),
+            role_arn: role.to_string(),
+            session_name: session_name.unwrap_or(DEFAULT_SESSION_NAME).to_string(),
+        })
+        .build();
+    SharedCredentialsProvider::new(
+        LazyCachingCredentialsProvider::builder()
+            .load(web_identity_provider)
+            .build(),
+    )
+}
+
+/// Returns the SDK's default chain of providers, without the slow lookup of the instance metadata
+pub fn get_provider_without_imds(region: Region) -> SharedCredentialsProvider {
+    let provider_config = ProviderConfig::without_region().with_region(Some(region));
+    let chain = CredentialsProviderChain::first_try(
+        "Environment",
+        EnvironmentVariableCredentialsProvider::new(),
+    )
+    .or_else(
+        "Profile",
+        ProfileFileCredentialsProvider::builder()
+            .configure(&provider_config)
+            .build(),
+    )
+    .or_else(
+        "WebIdentityToken",
+        WebIdentityTokenCredentialsProvider::builder()
+            .configure(&provider_config)
+            .build(),
+    )
+    .or_else(
+        "EcsContainer",
+        EcsCredentialsProvider::builder()
+            .configure(&provider_config)
+            .build(),
+    );
+    SharedCredentialsProvider::new(
+        LazyCachingCredentialsProvider::builder()
+            .load(chain)
+            .build(),
+    )
+}
 
 pub fn get_credentials_provider(
     base_provider: impl Into<SharedCredentialsProvider>,
@@ -9,7 +77,29 @@ pub fn get_credentials_provider(
     external_id: Option<&str>,
     session_name: Option<&str>,
     region: Region,
+    endpoint_url: Option<&str>,
 ) -> LazyCachingCredentialsProvider {
+    // The SDK's provider can't be pointed at another STS endpoint
+    if let Some(endpoint_url) = endpoint_url {
+        let endpoint = endpoint_url
+            .parse::<Uri>()
+            .expect("The STS endpoint should have been validated");
+        let sts_config = aws_sdk_sts::Config::builder()
+            .region(region)
+            .endpoint_resolver(Endpoint::immutable(endpoint))
+            .credentials_provider(base_provider.into())
+            .build();
+        let role_provider = EndpointAssumeRoleProvider {
+            client: aws_sdk_sts::Client::from_conf(sts_config),
+            role: role.to_string(),
+            external_id: external_id.map(String::from),
+            session_name: session_name.unwrap_or(DEFAULT_SESSION_NAME).to_string(),
+        };
+        return LazyCachingCredentialsProvider::builder()
+            .load(role_provider)
+            .build();
+    }
+
     let mut role_provider_builder = AssumeRoleProvider::builder(role).region(region);
     if let Some(external_id) = external_id {
         role_provider_builder = role_provider_builder.external_id(external_id)
@@ -23,3 +113,52 @@ pub fn get_credentials_provider(
         .load(role_provider)
         .build()
 }
+
+/// Assumes a role through a given STS endpoint, such as a VPC endpoint
+#[derive(Debug)]
+struct EndpointAssumeRoleProvider {
+    client: aws_sdk_sts::Client,
+    role: String,
+    external_id: Option<String>,
+    session_name: String,
+}
+
+impl EndpointAssumeRoleProvider {
+    async fn assume_role(&self) -> Result<Credentials, CredentialsError> {
+        let output = self
+            .client
+            .assume_role()
+            .role_arn(&self.role)
+            .set_external_id(self.external_id.clone())
+            .role_session_name(&self.session_name)
+            .send()
+            .await
+            .map_err(CredentialsError::provider_error)?;
+        let credentials = output
+            .credentials
+            .ok_or_else(|| CredentialsError::provider_error("STS returned no credentials"))?;
+        match (credentials.access_key_id, credentials.secret_access_key) {
+            (Some(access_key_id), Some(secret_access_key)) => Ok(Credentials::new(
+                access_key_id,
+                secret_access_key,
+                credentials.session_token,
+                credentials.expiration.map(|expiration| {
+                    UNIX_EPOCH + Duration::from_secs_f64(expiration.as_secs_f64())
+                }),
+                "AssumeRoleProvider",
+            )),
+            _ => Err(CredentialsError::provider_error(
+                "STS returned incomplete credentials",
+            )),
+        }
+    }
+}
+
+impl ProvideCredentials for EndpointAssumeRoleProvider {
+    fn provide_credentials<'a>(&'a self) -> future::ProvideCredentials<'a>
+    where
+        Self: 'a,
+    {
+        future::ProvideCredentials::new(self.assume_role())
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::{Arc, RwLock};
+
+/// The clusters to scrape, which can be swapped while scrapes are running
+///
+/// Scrapes work on a snapshot, so they never observe a partially replaced list.
+pub struct ClusterList {
+    current: RwLock<Arc<Vec<String>>>,
+}
+
+impl ClusterList {
+    pub fn new(cluster_names: Vec<String>) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(cluster_names)),
+        }
+    }
+
+    /// Returns the clusters as they are now, unaffected by later replacements
+    pub fn snapshot(&self) -> Arc<Vec<String>> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, cluster_names: Vec<String>) {
+        *self.current.write().unwrap() = Arc::new(cluster_names);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn generation(generation: usize) -> Vec<String> {
+        (0..50)
+            .map(|index| format!("gen{}-cluster{}", generation, index))
+            .collect()
+    }
+
+    #[test]
+    fn snapshot_is_unaffected_by_replace() {
+        let clusters = ClusterList::new(generation(0));
+        let snapshot = clusters.snapshot();
+        clusters.replace(generation(1));
+        assert_eq!(*snapshot, generation(0));
+        assert_eq!(*clusters.snapshot(), generation(1));
+    }
+
+    #[test]
+    fn snapshot_is_consistent_during_replace() {
+        let clusters = Arc::new(ClusterList::new(generation(0)));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let clusters = clusters.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let snapshot = clusters.snapshot();
+                        let prefix = snapshot[0].split('-').next().unwrap().to_string();
+                        assert!(snapshot.iter().all(|name| name.starts_with(&prefix)));
+                        assert_eq!(snapshot.len(), 50);
+                    }
+                })
+            })
+            .collect();
+        for index in 1..100 {
+            clusters.replace(generation(index));
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+}
@@ -1,119 +1,727 @@
 mod auth;
+mod clusters;
+mod quotas;
+mod target_health;
 
 use crate::exporter::Scraper;
 use async_trait::async_trait;
-use aws_sdk_ecs::model::{Failure, Resource};
+use aws_sdk_ecs::error::{
+    DescribeClustersError, DescribeContainerInstancesError, DescribeServicesError,
+    DescribeTaskDefinitionError, DescribeTasksError, ListContainerInstancesError,
+    ListServicesError, ListTasksError,
+};
+use aws_sdk_ecs::model::{
+    ClusterField, ContainerInstance, ContainerInstanceField, Deployment, DeploymentControllerType,
+    DeploymentRolloutState, DesiredStatus, Failure, LaunchType, Resource, SchedulingStrategy,
+    Service, ServiceField, Tag, Task, TaskDefinition, TaskStopCode,
+};
+use aws_sdk_ecs::SdkError;
+use aws_smithy_types::retry::{ErrorKind, ProvideErrorKind};
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
-use prometheus::{opts, register_int_gauge_vec_with_registry, IntGaugeVec, Registry};
+use futures::future::{join_all, try_join_all};
+use futures::{stream, StreamExt};
+use prometheus::{
+    histogram_opts, opts, register_gauge_vec_with_registry, register_histogram_vec_with_registry,
+    register_int_gauge_vec_with_registry, register_int_gauge_with_registry, GaugeVec, HistogramVec,
+    IntCounterVec, IntGaugeVec, Registry,
+};
+use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{Semaphore, SemaphorePermit};
 use tracing::warn;
 
-pub use auth::get_credentials_provider;
+pub use auth::{get_credentials_provider, get_provider_without_imds, get_web_identity_provider};
+use clusters::ClusterList;
+
+/// Default name of the label identifying the cluster
+pub const DEFAULT_CLUSTER_LABEL: &str = "cluster_name";
+
+/// Names of the other labels of the metric families, which the cluster label mustn't take
+///
+/// The labels of the tags exported on info metrics are also reserved, as they all start with
+/// `tag_`.
+pub const RESERVED_LABEL_NAMES: [&str; 43] = [
+    "agent_name",
+    "agent_version",
+    "ami_id",
+    "attribute",
+    "availability_zone",
+    "base",
+    "capacity_provider",
+    "cluster_arn",
+    "container_name",
+    "container_port",
+    "deployment_controller",
+    "deployment_id",
+    "desired_status",
+    "docker_version",
+    "ec2_instance_id",
+    "error_kind",
+    "family",
+    "image",
+    "instance_id",
+    "instance_type",
+    "last_status",
+    "launch_type",
+    "le",
+    "operation",
+    "private_ip",
+    "protocol",
+    "quantile",
+    "quota_name",
+    "region",
+    "resource",
+    "revision",
+    "rollout_state",
+    "scheduling_strategy",
+    "scraped_resource",
+    "service_name",
+    "state",
+    "statistic",
+    "status",
+    "stopped_reason",
+    "target_group_arn",
+    "task_arn",
+    "task_definition_family",
+    "weight",
+];
+
+/// Kinds of resources whose scrape can be disabled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Collector {
+    Services,
+    Instances,
+    Tasks,
+    Cluster,
+}
+
+impl Collector {
+    pub const NAMES: [&'static str; 4] = ["services", "instances", "tasks", "cluster"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "services" => Some(Self::Services),
+            "instances" => Some(Self::Instances),
+            "tasks" => Some(Self::Tasks),
+            "cluster" => Some(Self::Cluster),
+            _ => None,
+        }
+    }
+}
+
+/// Default prefix of the metrics' names
+pub const DEFAULT_METRICS_PREFIX: &str = "aws_ecs";
+
+/// Default number of concurrent describe calls per cluster
+pub const DEFAULT_MAX_CONCURRENCY: usize = 4;
+
+/// Default time allowed for scraping each kind of resource of a cluster
+pub const DEFAULT_SCRAPE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default number of stopped tasks described per cluster
+pub const DEFAULT_MAX_STOPPED_TASKS: usize = 100;
+
+/// Default number of clusters scraped concurrently
+pub const DEFAULT_CLUSTER_PARALLELISM: usize = 4;
+
+/// Maximum number of resources the API accepts in each describe call
+const DESCRIBE_SERVICES_CHUNK_SIZE: usize = 10;
+const DESCRIBE_INSTANCES_CHUNK_SIZE: usize = 100;
+
+/// Kinds of resource scraped for each cluster, as found in the `scraped_resource` label
+const SCRAPED_RESOURCES: [&str; 6] = [
+    "cluster_stats",
+    "cluster_instances",
+    "services",
+    "task_definitions",
+    "cluster_tasks",
+    "stopped_tasks",
+];
+
+/// Default number of times a throttled or failed call is retried
+pub const DEFAULT_MAX_RETRIES: usize = 3;
+
+/// Delay before the first retry, doubled for each of the following ones
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(200);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// Error codes ECS uses when throttling, which the SDK doesn't all classify as such
+const THROTTLING_ERROR_CODES: [&str; 3] = [
+    "ThrottlingException",
+    "TooManyRequestsException",
+    "RequestLimitExceeded",
+];
+
+/// Buckets of the scrape duration histogram, in seconds, covering typical API latencies
+const SCRAPE_DURATION_BUCKETS: [f64; 8] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 7.5, 10.0];
+
+/// How long the reachability probe may take before AWS is considered unreachable
+const REACHABILITY_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Label values identifying a cluster, which come first in the labels of all cluster metrics
+#[derive(Clone, Copy)]
+struct Cluster<'a> {
+    name: &'a str,
+    arn: Option<&'a str>,
+    region: Option<&'a str>,
+}
+
+impl<'a> Cluster<'a> {
+    /// Returns the cluster label values followed by the given ones
+    fn labels<'b>(&self, values: &[&'b str]) -> Vec<&'b str>
+    where
+        'a: 'b,
+    {
+        let mut result = vec![self.name];
+        result.extend(self.arn);
+        result.extend(self.region);
+        result.extend_from_slice(values);
+        result
+    }
+}
+
+/// Number of healthy and unhealthy targets behind each service's target groups
+struct TargetHealthMetrics {
+    healthy: IntGaugeVec,
+    unhealthy: IntGaugeVec,
+}
+
+/// How many list pages and describe chunks were needed to scrape a resource kind
+struct PaginationMetrics {
+    list_pages: IntGaugeVec,
+    describe_chunks: IntGaugeVec,
+}
+
+impl PaginationMetrics {
+    fn set(&self, cluster: Cluster<'_>, list_pages: usize, describe_chunks: usize) {
+        self.list_pages
+            .with_label_values(&cluster.labels(&[]))
+            .set(list_pages as i64);
+        self.describe_chunks
+            .with_label_values(&cluster.labels(&[]))
+            .set(describe_chunks as i64);
+    }
+
+    /// Removes the cluster's series, e.g. once it turns out to be hidden
+    fn remove(&self, cluster: Cluster<'_>) {
+        // Removal only fails for series which were never set, which is fine
+        let _ = self.list_pages.remove_label_values(&cluster.labels(&[]));
+        let _ = self
+            .describe_chunks
+            .remove_label_values(&cluster.labels(&[]));
+    }
+}
+
+/// Resources, as named by `filter_resources`, missing to run the services' tasks and left on
+/// the cluster's instances
+#[derive(Default)]
+struct CapacityEstimate {
+    demanded: HashMap<&'static str, i64>,
+    remaining: HashMap<&'static str, i64>,
+}
+
+impl CapacityEstimate {
+    /// Whether some resource is demanded in a larger amount than what is left
+    fn is_short(&self) -> bool {
+        self.demanded.iter().any(|(resource, demanded)| {
+            *demanded > self.remaining.get(resource).copied().unwrap_or_default()
+        })
+    }
+}
+
+/// Resource counts reported by the cluster itself
+struct ClusterStatsMetrics {
+    registered_instances: IntGaugeVec,
+    active_services: IntGaugeVec,
+    running_tasks: IntGaugeVec,
+    pending_tasks: IntGaugeVec,
+    statistic: IntGaugeVec,
+}
+
+impl ClusterStatsMetrics {
+    fn set(&self, cluster: Cluster<'_>, cluster_details: &aws_sdk_ecs::model::Cluster) {
+        let labels = cluster.labels(&[]);
+        self.registered_instances
+            .with_label_values(&labels)
+            .set(cluster_details.registered_container_instances_count as i64);
+        self.active_services
+            .with_label_values(&labels)
+            .set(cluster_details.active_services_count as i64);
+        self.running_tasks
+            .with_label_values(&labels)
+            .set(cluster_details.running_tasks_count as i64);
+        self.pending_tasks
+            .with_label_values(&labels)
+            .set(cluster_details.pending_tasks_count as i64);
+
+        // Statistics are returned as strings, e.g. `runningFargateTasksCount=3`
+        for statistic in cluster_details.statistics.iter().flatten() {
+            let value = statistic
+                .value
+                .as_deref()
+                .and_then(|value| value.parse().ok());
+            if let (Some(name), Some(value)) = (&statistic.name, value) {
+                self.statistic
+                    .with_label_values(&cluster.labels(&[name.as_str()]))
+                    .set(value);
+            }
+        }
+    }
+}
+
+/// Metric families filled in by the scrape of each cluster
+struct ClusterMetricFamilies {
+    scrape_metric: IntGaugeVec,
+    task_metric_family: IntGaugeVec,
+    resource_metric_family_registered: IntGaugeVec,
+    resource_metric_family_remaining: IntGaugeVec,
+    ports_metric_family: IntGaugeVec,
+    instance_status_metric_family: IntGaugeVec,
+    instance_status_info_metric_family: IntGaugeVec,
+    instance_info_metric_family: IntGaugeVec,
+    agent_connected_metric_family: IntGaugeVec,
+    agent_info_metric_family: IntGaugeVec,
+    attribute_metric_family: IntGaugeVec,
+    service_metric_family_current: IntGaugeVec,
+    service_metric_family_desired: IntGaugeVec,
+    service_missing_metric_family: IntGaugeVec,
+    service_info_metric_family: IntGaugeVec,
+    cluster_info_metric_family: IntGaugeVec,
+    service_created_metric_family: GaugeVec,
+    deployment_updated_metric_family: GaugeVec,
+    last_deployment_metric_family: GaugeVec,
+    execute_command_metric_family: IntGaugeVec,
+    deployment_blocked_metric_family: IntGaugeVec,
+    deployment_metric_family: IntGaugeVec,
+    deployments_total_metric_family: IntGaugeVec,
+    load_balancer_metric_family: IntGaugeVec,
+    image_metric_family: IntGaugeVec,
+    task_definition_cpu_metric_family: IntGaugeVec,
+    task_definition_memory_metric_family: IntGaugeVec,
+    managed_agent_metric_family: IntGaugeVec,
+    task_info_metric_family: IntGaugeVec,
+    tasks_total_metric_family: IntGaugeVec,
+    stopped_tasks_metric_family: IntGaugeVec,
+    service_pagination_metrics: PaginationMetrics,
+    target_health_metrics: TargetHealthMetrics,
+    instance_pagination_metrics: PaginationMetrics,
+    capacity_provider_metric_family: IntGaugeVec,
+    default_capacity_provider_metric_family: IntGaugeVec,
+    capacity_shortfall_metric_family: Option<IntGaugeVec>,
+    quota_usage_metric_family: GaugeVec,
+    scrape_duration_metric_family: HistogramVec,
+    cluster_stats_metrics: ClusterStatsMetrics,
+}
 
 pub struct EcsClient {
     client: aws_sdk_ecs::Client,
-    cluster_names: Vec<String>,
+    // Clients for the clusters which are accessed with their own role
+    cluster_clients: HashMap<String, aws_sdk_ecs::Client>,
+    clusters: ClusterList,
+    service_include: Vec<ServiceField>,
+    instance_include: Vec<ContainerInstanceField>,
+    quotas_client: Option<aws_sdk_servicequotas::Client>,
+    elb_client: Option<aws_sdk_elasticloadbalancingv2::Client>,
+    with_images: bool,
+    with_task_definitions: bool,
+    disabled_collectors: Vec<Collector>,
+    // Task definition revisions are immutable, so they can be kept for the life of the process
+    task_definitions: Mutex<HashMap<String, TaskDefinition>>,
+    max_concurrency: usize,
+    cluster_concurrency: HashMap<String, usize>,
+    // Limits the number of concurrent describe calls for each cluster
+    cluster_semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+    cluster_parallelism: usize,
+    scrape_timeout: Duration,
+    // Limits the number of concurrent describe calls across all clusters, and all clients sharing it
+    shared_semaphore: Option<Arc<Semaphore>>,
+    // Attributes, as `name` or `name=value`, for which to count the matching instances
+    counted_attributes: Vec<String>,
+    with_managed_agents: bool,
+    with_tasks: bool,
+    // Number of stopped tasks described per cluster, if they're scraped at all
+    max_stopped_tasks: Option<usize>,
+    discover_clusters: bool,
+    // Tags the discovered clusters must all have to be scraped
+    cluster_tags: Vec<(String, String)>,
+    // Tag keys exported as labels, along with the name of their label
+    tag_labels: Vec<(String, String)>,
+    cluster_label: String,
+    hide_empty_clusters: bool,
+    emit_cluster_arn: bool,
+    instance_ip_label: bool,
+    with_capacity_shortfall: bool,
+    omit_registered_resources: bool,
+    service_name_include: Option<Regex>,
+    service_name_exclude: Option<Regex>,
+    describe_chunk_size: Option<usize>,
+    // Only set when clusters span several regions, in which case they get a `region` label
+    default_region: Option<String>,
+    cluster_regions: HashMap<String, String>,
+    max_retries: usize,
+    // Count across scrapes, so they're registered in each scrape's registry
+    throttled_metric_family: IntCounterVec,
+    api_calls_metric_family: IntCounterVec,
+    account_api_calls_metric_family: IntCounterVec,
+    // Created on first use, as its labels depend on the settings
+    scrape_errors_metric_family: Mutex<Option<IntCounterVec>>,
+    metrics_prefix: String,
+    // Cluster ARNs don't change, so they only need to be resolved once
+    cluster_arns: Mutex<HashMap<String, String>>,
 }
 
 impl EcsClient {
+    /// Creates a client for the given clusters, ignoring those given more than once
     pub fn new<C: AsRef<str>>(client: aws_sdk_ecs::Client, cluster_names: &[C]) -> Self {
+        let mut unique_names: Vec<String> = Vec::with_capacity(cluster_names.len());
+        for cluster_name in cluster_names.iter().map(AsRef::as_ref) {
+            if unique_names.iter().any(|name| name == cluster_name) {
+                warn!("Ignoring duplicate cluster `{}`", cluster_name);
+            } else {
+                unique_names.push(cluster_name.to_owned());
+            }
+        }
         Self {
             client,
-            cluster_names: cluster_names
-                .iter()
-                .map(|x| x.as_ref().to_owned())
-                .collect(),
+            cluster_clients: HashMap::new(),
+            clusters: ClusterList::new(unique_names),
+            service_include: vec![],
+            instance_include: vec![],
+            quotas_client: None,
+            elb_client: None,
+            with_images: false,
+            with_task_definitions: false,
+            disabled_collectors: vec![],
+            task_definitions: Mutex::new(HashMap::new()),
+            max_concurrency: DEFAULT_MAX_CONCURRENCY,
+            cluster_concurrency: HashMap::new(),
+            cluster_semaphores: Mutex::new(HashMap::new()),
+            cluster_parallelism: DEFAULT_CLUSTER_PARALLELISM,
+            scrape_timeout: DEFAULT_SCRAPE_TIMEOUT,
+            shared_semaphore: None,
+            counted_attributes: vec![],
+            with_managed_agents: false,
+            with_tasks: false,
+            max_stopped_tasks: None,
+            discover_clusters: false,
+            cluster_tags: vec![],
+            tag_labels: vec![],
+            cluster_label: DEFAULT_CLUSTER_LABEL.to_string(),
+            hide_empty_clusters: false,
+            emit_cluster_arn: false,
+            instance_ip_label: false,
+            with_capacity_shortfall: false,
+            omit_registered_resources: false,
+            service_name_include: None,
+            service_name_exclude: None,
+            describe_chunk_size: None,
+            default_region: None,
+            cluster_regions: HashMap::new(),
+            max_retries: DEFAULT_MAX_RETRIES,
+            throttled_metric_family: throttled_metric_family(DEFAULT_METRICS_PREFIX),
+            api_calls_metric_family: api_calls_metric_family(
+                DEFAULT_METRICS_PREFIX,
+                DEFAULT_CLUSTER_LABEL,
+            ),
+            account_api_calls_metric_family: account_api_calls_metric_family(
+                DEFAULT_METRICS_PREFIX,
+            ),
+            scrape_errors_metric_family: Mutex::new(None),
+            metrics_prefix: DEFAULT_METRICS_PREFIX.to_string(),
+            cluster_arns: Mutex::new(HashMap::new()),
         }
     }
 
-    async fn get_service_names(&self, cluster_name: &str) -> Result<Vec<String>> {
-        let mut next_token = None;
-        let mut result = vec![];
+    /// Adds a `cluster_arn` label to all cluster metrics
+    pub fn with_cluster_arn_label(mut self, emit_cluster_arn: bool) -> Self {
+        self.emit_cluster_arn = emit_cluster_arn;
+        self
+    }
+
+    /// Adds a `private_ip` label to the container instance metrics
+    pub fn with_instance_ip_label(mut self, instance_ip_label: bool) -> Self {
+        self.instance_ip_label = instance_ip_label;
+        self
+    }
+
+    /// Estimates whether the services' missing tasks can be placed on the remaining resources
+    pub fn with_capacity_shortfall(mut self, with_capacity_shortfall: bool) -> Self {
+        self.with_capacity_shortfall = with_capacity_shortfall;
+        self
+    }
+
+    /// Skips the registered resources, which rarely change, keeping only the remaining ones
+    pub fn with_omit_registered_resources(mut self, omit_registered_resources: bool) -> Self {
+        self.omit_registered_resources = omit_registered_resources;
+        self
+    }
+
+    /// Only exports the services whose name matches `include`, if set, and doesn't match `exclude`
+    pub fn with_service_name_filter(
+        mut self,
+        service_name_include: Option<Regex>,
+        service_name_exclude: Option<Regex>,
+    ) -> Self {
+        self.service_name_include = service_name_include;
+        self.service_name_exclude = service_name_exclude;
+        self
+    }
+
+    /// Requests fewer resources in each describe call, e.g. to spread them out when throttled
+    ///
+    /// The size is capped to what each describe call accepts.
+    pub fn with_describe_chunk_size(mut self, describe_chunk_size: Option<usize>) -> Self {
+        self.describe_chunk_size = describe_chunk_size;
+        self
+    }
+
+    /// Prefixes the names of the metrics with the given one instead of `aws_ecs`
+    pub fn with_metrics_prefix(mut self, metrics_prefix: &str) -> Self {
+        self.throttled_metric_family = throttled_metric_family(metrics_prefix);
+        self.api_calls_metric_family = api_calls_metric_family(metrics_prefix, &self.cluster_label);
+        self.account_api_calls_metric_family = account_api_calls_metric_family(metrics_prefix);
+        self.metrics_prefix = metrics_prefix.to_string();
+        self
+    }
+
+    fn metric_name(&self, name: &str) -> String {
+        format!("{}_{}", self.metrics_prefix, name)
+    }
+
+    /// Sets how many times throttled or failed calls are retried before giving up
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Sends the request, retrying it with a jittered exponential backoff on throttling and
+    /// transient errors
+    ///
+    /// Other errors are returned right away. Each attempt is counted as a call to the cluster.
+    async fn with_retries<T, E, F, Fut>(
+        &self,
+        operation: &str,
+        cluster_name: &str,
+        send: F,
+    ) -> std::result::Result<T, SdkError<E>>
+    where
+        E: ProvideErrorKind,
+        F: Fn() -> Fut,
+        Fut: Future<Output = std::result::Result<T, SdkError<E>>>,
+    {
+        let mut attempt = 0;
         loop {
-            let response = self
-                .client
-                .list_services()
-                .cluster(cluster_name)
-                .set_next_token(next_token)
-                .send()
-                .await?;
-            if let Some(arn_vec) = response.service_arns {
-                result.extend(arn_vec)
+            self.count_api_call(operation, cluster_name);
+            let err = match send().await {
+                Ok(response) => return Ok(response),
+                Err(err) => err,
+            };
+            let throttled = is_throttling(&err);
+            if throttled {
+                self.throttled_metric_family
+                    .with_label_values(&[operation])
+                    .inc();
             }
-            next_token = response.next_token;
-            if next_token.is_none() {
-                break;
+            if attempt >= self.max_retries || !(throttled || is_transient(&err)) {
+                return Err(err);
             }
+            tokio::time::sleep(retry_backoff(attempt)).await;
+            attempt += 1;
         }
-        Ok(result)
     }
 
-    /// Returns the details of the given services
-    ///
-    /// This will only return an `Err` if the request itself fails.
-    /// In case of missing resources, it will only log the failures and the result will contain
-    /// those resources which were found.
-    async fn get_services_details(
+    fn count_api_call(&self, operation: &str, cluster_name: &str) {
+        self.api_calls_metric_family
+            .with_label_values(&[operation, cluster_name])
+            .inc();
+    }
+
+    /// Counts a call which isn't specific to a cluster, such as listing them
+    fn count_account_api_call(&self, operation: &str) {
+        self.account_api_calls_metric_family
+            .with_label_values(&[operation])
+            .inc();
+    }
+
+    fn scrape_errors_metric_family(&self) -> IntCounterVec {
+        self.scrape_errors_metric_family
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| {
+                IntCounterVec::new(
+                    opts!(
+                        self.metric_name("cluster_scrape_errors_total"),
+                        "Number of failed scrapes of a particular cluster and resource kind, by cause"
+                    ),
+                    &self.cluster_label_names(&["scraped_resource", "error_kind"]),
+                )
+                .expect("Failed to create aws_ecs_cluster_scrape_errors_total metric family")
+            })
+            .clone()
+    }
+
+    /// Counts the failed scrape, returning the kind of error it was
+    fn count_scrape_error(
         &self,
-        cluster: &str,
-        service_names: Vec<&str>,
-    ) -> Result<Vec<aws_sdk_ecs::model::Service>> {
-        let mut result = vec![];
+        cluster: Cluster<'_>,
+        scraped_resource: &str,
+        err: &color_eyre::Report,
+    ) -> &'static str {
+        let error_kind = scrape_error_kind(err);
+        self.scrape_errors_metric_family()
+            .with_label_values(&cluster.labels(&[scraped_resource, error_kind]))
+            .inc();
+        error_kind
+    }
 
-        for chunk in service_names.chunks(10) {
-            let response = self
-                .client
-                .describe_services()
-                .cluster(cluster)
-                .set_services(Some(chunk.iter().map(|x| x.to_string()).collect()))
-                .send()
-                .await?;
-            log_failures(response.failures);
-            if let Some(s) = response.services {
-                result.extend(s);
-            }
+    /// Returns the fields to include when describing services, with their tags if needed
+    fn service_include(&self) -> Vec<ServiceField> {
+        let mut result = self.service_include.clone();
+        if !self.tag_labels.is_empty() && !result.contains(&ServiceField::Tags) {
+            result.push(ServiceField::Tags);
         }
-        Ok(result)
+        result
     }
 
-    async fn get_service_metrics(
+    /// Returns the fields to include when describing clusters, with their tags if needed
+    fn cluster_include(&self) -> Vec<ClusterField> {
+        let mut result = vec![ClusterField::Statistics];
+        if !self.tag_labels.is_empty() {
+            result.push(ClusterField::Tags);
+        }
+        result
+    }
+
+    fn services_chunk_size(&self) -> usize {
+        self.describe_chunk_size
+            .map_or(DESCRIBE_SERVICES_CHUNK_SIZE, |size| {
+                size.clamp(1, DESCRIBE_SERVICES_CHUNK_SIZE)
+            })
+    }
+
+    fn instances_chunk_size(&self) -> usize {
+        self.describe_chunk_size
+            .map_or(DESCRIBE_INSTANCES_CHUNK_SIZE, |size| {
+                size.clamp(1, DESCRIBE_INSTANCES_CHUNK_SIZE)
+            })
+    }
+
+    /// Adds a `region` label to the cluster metrics, with the given region for the clusters not
+    /// set up with `with_cluster_region`
+    pub fn with_region_label(mut self, default_region: &str) -> Self {
+        self.default_region = Some(default_region.to_string());
+        self
+    }
+
+    /// Records the region the cluster's client queries, for the `region` label
+    pub fn with_cluster_region(mut self, cluster: &str, region: &str) -> Self {
+        self.cluster_regions
+            .insert(cluster.to_string(), region.to_string());
+        self
+    }
+
+    /// Queries the cluster with the given client, e.g. one assuming another account's role,
+    /// instead of the default one
+    pub fn with_cluster_client(mut self, cluster: &str, client: aws_sdk_ecs::Client) -> Self {
+        self.cluster_clients.insert(cluster.to_string(), client);
+        self
+    }
+
+    /// Returns the client to query the cluster with
+    fn client(&self, cluster: &str) -> &aws_sdk_ecs::Client {
+        self.cluster_clients.get(cluster).unwrap_or(&self.client)
+    }
+
+    /// Groups the clusters by the client to query them with, so that batched calls don't mix
+    /// clusters from different accounts
+    fn group_by_client(
         &self,
-        cluster: &str,
-        service_metric_family_desired: &IntGaugeVec,
-        service_metric_family_current: &IntGaugeVec,
-    ) -> Result<()> {
-        let svc_list = self.get_service_names(cluster).await?;
-        let services = self
-            .get_services_details(cluster, svc_list.iter().map(String::as_ref).collect())
-            .await?;
+        cluster_names: &[String],
+    ) -> Vec<(&aws_sdk_ecs::Client, Vec<String>)> {
+        let mut default_group = vec![];
+        let mut result = vec![];
+        for cluster_name in cluster_names {
+            match self.cluster_clients.get(cluster_name) {
+                Some(client) => result.push((client, vec![cluster_name.clone()])),
+                None => default_group.push(cluster_name.clone()),
+            }
+        }
+        result.push((&self.client, default_group));
+        result
+    }
 
-        for service in services {
-            service_metric_family_desired
-                .with_label_values(&[cluster, service.service_name.as_ref().unwrap()])
-                .set(service.desired_count as i64);
-            service_metric_family_current
-                .with_label_values(&[cluster, service.service_name.as_ref().unwrap(), "running"])
-                .set(service.running_count as i64);
-            service_metric_family_current
-                .with_label_values(&[cluster, service.service_name.as_ref().unwrap(), "pending"])
-                .set(service.pending_count as i64);
+    /// Returns the names of the labels identifying the cluster followed by the given ones
+    fn cluster_label_names<'a>(&'a self, names: &[&'a str]) -> Vec<&'a str> {
+        let mut result = vec![self.cluster_label.as_str()];
+        if self.emit_cluster_arn {
+            result.push("cluster_arn");
+        }
+        if self.default_region.is_some() {
+            result.push("region");
+        }
+        result.extend_from_slice(names);
+        result
+    }
+
+    /// Returns the names of the labels identifying an instance followed by the given ones
+    fn instance_label_names<'a>(&self, names: &[&'a str]) -> Vec<&'a str> {
+        let mut result = vec!["ec2_instance_id", "instance_id"];
+        if self.instance_ip_label {
+            result.push("private_ip");
         }
+        result.extend_from_slice(names);
+        result
+    }
 
-        Ok(())
+    /// Returns the details of the cluster, including its statistics
+    ///
+    /// Each cluster is described on its own, so that one failing doesn't affect the others.
+    async fn get_cluster_details(&self, cluster_name: &str) -> Result<aws_sdk_ecs::model::Cluster> {
+        let request = self
+            .client(cluster_name)
+            .describe_clusters()
+            .clusters(cluster_name)
+            .set_include(Some(self.cluster_include()));
+        let response = self
+            .with_retries("DescribeClusters", cluster_name, || request.clone().send())
+            .await?;
+        log_failures(response.failures);
+        response
+            .clusters
+            .into_iter()
+            .flatten()
+            .next()
+            .ok_or_else(|| eyre!("Cluster `{}` wasn't found", cluster_name))
     }
 
-    async fn get_container_instance_names(&self, cluster_name: &str) -> Result<Vec<String>> {
+    /// Returns the names of all the clusters of the account, remembering their ARNs
+    async fn list_cluster_names(&self) -> Result<Vec<String>> {
         let mut next_token = None;
         let mut result = vec![];
         loop {
+            self.count_account_api_call("ListClusters");
             let response = self
                 .client
-                .list_container_instances()
-                .cluster(cluster_name)
+                .list_clusters()
                 .set_next_token(next_token)
                 .send()
                 .await?;
-            if let Some(arn_vec) = response.container_instance_arns {
-                result.extend(arn_vec)
+            for arn in response.cluster_arns.into_iter().flatten() {
+                // Cluster ARNs end with `cluster/NAME`
+                let name = arn.rsplit_once('/').map_or(arn.as_str(), |(_, name)| name);
+                result.push(name.to_string());
+                self.cluster_arns
+                    .lock()
+                    .unwrap()
+                    .insert(name.to_string(), arn.clone());
             }
             next_token = response.next_token;
             if next_token.is_none() {
@@ -123,213 +731,2489 @@ impl EcsClient {
         Ok(result)
     }
 
-    async fn get_container_instance_details(
-        &self,
-        cluster: &str,
-        instance_names: Vec<&str>,
-    ) -> Result<Vec<aws_sdk_ecs::model::ContainerInstance>> {
+    /// Returns the clusters having all the configured tags
+    async fn filter_clusters_by_tags(&self, cluster_names: Vec<String>) -> Result<Vec<String>> {
         let mut result = vec![];
-
-        for chunk in instance_names.chunks(10) {
+        for chunk in cluster_names.chunks(100) {
+            self.count_account_api_call("DescribeClusters");
             let response = self
                 .client
-                .describe_container_instances()
-                .cluster(cluster)
-                .set_container_instances(Some(chunk.iter().map(|x| x.to_string()).collect()))
+                .describe_clusters()
+                .set_clusters(Some(chunk.to_vec()))
+                .include(ClusterField::Tags)
                 .send()
                 .await?;
             log_failures(response.failures);
-            if let Some(s) = response.container_instances {
-                result.extend(s);
+            for cluster in response.clusters.into_iter().flatten() {
+                let tags = cluster.tags.as_deref().unwrap_or_default();
+                let has_all_tags = self.cluster_tags.iter().all(|(key, value)| {
+                    tags.iter().any(|tag| {
+                        tag.key.as_ref() == Some(key) && tag.value.as_ref() == Some(value)
+                    })
+                });
+                if has_all_tags {
+                    result.extend(cluster.cluster_name);
+                }
             }
         }
+        warn!(
+            "Scraping {} of the {} discovered clusters having the required tags",
+            result.len(),
+            cluster_names.len()
+        );
         Ok(result)
     }
 
-    async fn get_container_instance_metrics(
-        &self,
-        cluster: &str,
-        task_metric_family: &IntGaugeVec,
-        resource_metric_family_registered: &IntGaugeVec,
-        resource_metric_family_remaining: &IntGaugeVec,
-    ) -> Result<()> {
-        let instance_name_list = self.get_container_instance_names(cluster).await?;
-        let instances = self
-            .get_container_instance_details(
-                cluster,
-                instance_name_list.iter().map(String::as_ref).collect(),
-            )
-            .await?;
-
-        for instance in instances {
-            task_metric_family
-                .with_label_values(&[
-                    cluster,
-                    instance.ec2_instance_id.as_ref().unwrap(),
-                    "running",
-                ])
-                .set(instance.running_tasks_count as i64);
-            task_metric_family
-                .with_label_values(&[
-                    cluster,
-                    instance.ec2_instance_id.as_ref().unwrap(),
-                    "pending",
-                ])
-                .set(instance.pending_tasks_count as i64);
-
-            if let Some(remaining_resources) = &instance.remaining_resources {
-                let resources: Vec<(&str, i64)> = remaining_resources
-                    .iter()
-                    .filter_map(filter_resources)
-                    .collect();
-                for resource in resources {
-                    resource_metric_family_remaining
-                        .with_label_values(&[
-                            cluster,
-                            instance.ec2_instance_id.as_ref().unwrap(),
-                            resource.0,
-                        ])
-                        .set(resource.1);
+    /// Returns the ARN of each configured cluster, indexed by the name it was configured with
+    ///
+    /// Clusters configured by ARN don't need to be resolved. Resolution failures are only logged
+    /// and the affected clusters are missing from the result.
+    async fn get_cluster_arns(&self, cluster_names: &[String]) -> HashMap<String, String> {
+        let unresolved: Vec<String> = {
+            let mut cluster_arns = self.cluster_arns.lock().unwrap();
+            for cluster_name in cluster_names {
+                if cluster_name.starts_with("arn:") {
+                    cluster_arns.insert(cluster_name.clone(), cluster_name.clone());
                 }
             }
+            cluster_names
+                .iter()
+                .filter(|name| !cluster_arns.contains_key(*name))
+                .cloned()
+                .collect()
+        };
 
-            // TODO: Properly handle all resource types
-            if let Some(registered_resources) = &instance.registered_resources {
-                let resources: Vec<(&str, i64)> = registered_resources
-                    .iter()
-                    .filter_map(filter_resources)
-                    .collect();
-                for resource in resources {
-                    resource_metric_family_registered
-                        .with_label_values(&[
-                            cluster,
-                            instance.ec2_instance_id.as_ref().unwrap(),
-                            resource.0,
-                        ])
-                        .set(resource.1);
+        let chunks = self
+            .group_by_client(&unresolved)
+            .into_iter()
+            .flat_map(|(client, names)| {
+                names
+                    .chunks(100)
+                    .map(|chunk| (client, chunk.to_vec()))
+                    .collect::<Vec<_>>()
+            });
+        for (client, chunk) in chunks {
+            self.count_account_api_call("DescribeClusters");
+            match client
+                .describe_clusters()
+                .set_clusters(Some(chunk.to_vec()))
+                .send()
+                .await
+            {
+                Ok(response) => {
+                    log_failures(response.failures);
+                    let mut cluster_arns = self.cluster_arns.lock().unwrap();
+                    for cluster in response.clusters.into_iter().flatten() {
+                        if let (Some(name), Some(arn)) = (cluster.cluster_name, cluster.cluster_arn)
+                        {
+                            cluster_arns.insert(name, arn);
+                        }
+                    }
                 }
+                Err(err) => warn!("Failed to resolve cluster ARNs: {}", err),
             }
         }
 
-        Ok(())
+        self.cluster_arns.lock().unwrap().clone()
     }
-}
 
-#[async_trait]
-impl Scraper for EcsClient {
-    async fn scrape(&self) -> Result<Registry> {
-        let registry = Registry::new();
-        let scrape_metric = register_int_gauge_vec_with_registry!(
-            opts!(
-                "aws_ecs_cluster_scrape_success",
-                "Whether the scrape for a particular cluster and resource kind was successful"
-            ),
-            &["cluster_name", "scraped_resource"],
-            registry
-        )
-        .expect("Failed to generate aws_ecs_cluster_scrape_success metric");
+    /// Omits all the series of clusters where no resources were found
+    ///
+    /// Clusters for which a scrape failed are always shown.
+    pub fn with_hide_empty_clusters(mut self, hide_empty_clusters: bool) -> Self {
+        self.hide_empty_clusters = hide_empty_clusters;
+        self
+    }
 
-        let task_metric_family = register_int_gauge_vec_with_registry!(
-            opts!(
-                "aws_ecs_instance_tasks_total",
-                "Tasks running on the Container Instances (ec2)"
-            ),
-            &["cluster_name", "ec2_instance_id", "state"],
-            registry
-        )
-        .expect("Failed to register aws_ecs_instance_tasks metric family");
+    /// Sets the name of the label identifying the cluster in all metrics
+    pub fn with_cluster_label(mut self, cluster_label: &str) -> Self {
+        self.cluster_label = cluster_label.to_string();
+        self.api_calls_metric_family = api_calls_metric_family(&self.metrics_prefix, cluster_label);
+        self
+    }
 
-        let resource_metric_family_registered = register_int_gauge_vec_with_registry!(
-            opts!(
-                "aws_ecs_instance_resources_registered",
-                "Initial resources available on ECS Container Instance"
-            ),
-            &["cluster_name", "ec2_instance_id", "resource"],
-            registry
-        )
-        .expect("Failed to register aws_ecs_instance_resources_registered metric family");
+    /// Scrapes all the clusters of the account, as listed at the start of each scrape, instead of
+    /// the configured ones
+    pub fn with_cluster_discovery(mut self, discover_clusters: bool) -> Self {
+        self.discover_clusters = discover_clusters;
+        self
+    }
 
-        let resource_metric_family_remaining = register_int_gauge_vec_with_registry!(
-            opts!(
-                "aws_ecs_instance_resources_remaining",
-                "Initial resources available on ECS Container Instance"
-            ),
-            &["cluster_name", "ec2_instance_id", "resource"],
-            registry
-        )
-        .expect("Failed to register aws_ecs_instance_resources_remaining metric family");
+    /// Adds the values of these tags to the service and cluster info metrics, as `tag_KEY` labels
+    ///
+    /// Keys are sanitized into label names, and those ending up with an existing name are ignored.
+    pub fn with_tag_labels<K: AsRef<str>>(mut self, tag_keys: &[K]) -> Self {
+        for tag_key in tag_keys.iter().map(AsRef::as_ref) {
+            let label = tag_label_name(tag_key);
+            if self
+                .tag_labels
+                .iter()
+                .any(|(_, existing)| *existing == label)
+            {
+                warn!(
+                    "Ignoring tag `{}`, as its label `{}` is already used",
+                    tag_key, label
+                );
+            } else {
+                self.tag_labels.push((tag_key.to_string(), label));
+            }
+        }
+        self
+    }
 
-        let service_metric_family_current = register_int_gauge_vec_with_registry!(
-            opts!(
-                "aws_ecs_service_current_total",
-                "Current Number of ECS Services"
-            ),
-            &["cluster_name", "service_name", "state"],
-            registry
-        )
-        .expect("Failed to generate aws_ecs_service metric family");
+    /// Returns the given label names followed by those of the tags
+    fn tag_label_names<'a>(&'a self, names: &[&'a str]) -> Vec<&'a str> {
+        let mut result = names.to_vec();
+        result.extend(self.tag_labels.iter().map(|(_, label)| label.as_str()));
+        result
+    }
 
-        let service_metric_family_desired = register_int_gauge_vec_with_registry!(
-            opts!("aws_ecs_service_desired", "Desired Number of ECS Services"),
-            &["cluster_name", "service_name"],
-            registry
+    /// Returns the value of each tag exported as a label, empty for those which aren't set
+    fn tag_label_values<'a>(&self, tags: Option<&'a [Tag]>) -> Vec<&'a str> {
+        self.tag_labels
+            .iter()
+            .map(|(key, _)| {
+                tags.into_iter()
+                    .flatten()
+                    .find(|tag| tag.key.as_ref() == Some(key))
+                    .and_then(|tag| tag.value.as_deref())
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// Only scrapes the discovered clusters having all these tags
+    pub fn with_cluster_tags(mut self, cluster_tags: Vec<(String, String)>) -> Self {
+        self.cluster_tags = cluster_tags;
+        self
+    }
+
+    pub fn with_tasks(mut self, with_tasks: bool) -> Self {
+        self.with_tasks = with_tasks;
+        self
+    }
+
+    /// Enables exporting why tasks stopped, describing at most `max_stopped_tasks` per cluster
+    pub fn with_stopped_tasks(mut self, max_stopped_tasks: Option<usize>) -> Self {
+        self.max_stopped_tasks = max_stopped_tasks;
+        self
+    }
+
+    /// Enables exporting the status of the tasks' managed agents, e.g. for ECS Exec
+    pub fn with_managed_agents(mut self, with_managed_agents: bool) -> Self {
+        self.with_managed_agents = with_managed_agents;
+        self
+    }
+
+    /// Sets the attributes for which to count the eligible instances
+    ///
+    /// Each attribute is either a name, which only needs to be present on the instance,
+    /// or `name=value`, which must match exactly.
+    pub fn with_attribute_counts(mut self, counted_attributes: Vec<String>) -> Self {
+        self.counted_attributes = counted_attributes;
+        self
+    }
+
+    /// Sets how many describe calls may run concurrently for each cluster
+    ///
+    /// Clusters without an override use `max_concurrency`.
+    pub fn with_concurrency(
+        mut self,
+        max_concurrency: usize,
+        cluster_overrides: &HashMap<String, usize>,
+    ) -> Self {
+        self.max_concurrency = max_concurrency;
+        self.cluster_concurrency = cluster_overrides.clone();
+        self
+    }
+
+    /// Sets how long scraping each kind of resource of a cluster may take before it's failed
+    pub fn with_scrape_timeout(mut self, scrape_timeout: Duration) -> Self {
+        self.scrape_timeout = scrape_timeout;
+        self
+    }
+
+    /// Sets how many clusters are scraped concurrently
+    pub fn with_cluster_parallelism(mut self, cluster_parallelism: usize) -> Self {
+        self.cluster_parallelism = cluster_parallelism;
+        self
+    }
+
+    /// Bounds the describe calls of this client together with those of every client sharing the
+    /// semaphore, e.g. one per region
+    pub fn with_shared_concurrency(mut self, shared_semaphore: Arc<Semaphore>) -> Self {
+        self.shared_semaphore = Some(shared_semaphore);
+        self
+    }
+
+    /// Waits until a describe call is allowed by both the cluster's and the shared limits
+    ///
+    /// The cluster's permit is taken first, so that waiting for it doesn't hold a shared one.
+    async fn acquire<'a>(
+        &'a self,
+        semaphore: &'a Semaphore,
+    ) -> Result<(SemaphorePermit<'a>, Option<SemaphorePermit<'a>>)> {
+        let permit = semaphore.acquire().await?;
+        let shared_permit = match &self.shared_semaphore {
+            Some(shared_semaphore) => Some(shared_semaphore.acquire().await?),
+            None => None,
+        };
+        Ok((permit, shared_permit))
+    }
+
+    /// Returns the semaphore limiting the concurrent describe calls for the cluster
+    fn semaphore(&self, cluster: &str) -> Arc<Semaphore> {
+        self.cluster_semaphores
+            .lock()
+            .unwrap()
+            .entry(cluster.to_string())
+            .or_insert_with(|| {
+                let permits = self
+                    .cluster_concurrency
+                    .get(cluster)
+                    .copied()
+                    .unwrap_or(self.max_concurrency);
+                Arc::new(Semaphore::new(permits))
+            })
+            .clone()
+    }
+
+    /// Enables exporting the container images of each service's task definition
+    pub fn with_images(mut self, with_images: bool) -> Self {
+        self.with_images = with_images;
+        self
+    }
+
+    /// Enables exporting the CPU and memory registered by each service's task definition
+    pub fn with_task_definitions(mut self, with_task_definitions: bool) -> Self {
+        self.with_task_definitions = with_task_definitions;
+        self
+    }
+
+    /// Skips scraping the given kinds of resources, along with their scrape success
+    pub fn with_disabled_collectors(mut self, disabled_collectors: Vec<Collector>) -> Self {
+        self.disabled_collectors = disabled_collectors;
+        self
+    }
+
+    fn is_enabled(&self, collector: Collector) -> bool {
+        !self.disabled_collectors.contains(&collector)
+    }
+
+    /// Enables fetching the health of the services' targets from their load balancers
+    pub fn with_target_health(
+        mut self,
+        elb_client: aws_sdk_elasticloadbalancingv2::Client,
+    ) -> Self {
+        self.elb_client = Some(elb_client);
+        self
+    }
+
+    /// Enables fetching the account's ECS quotas on every scrape
+    pub fn with_quotas(mut self, quotas_client: aws_sdk_servicequotas::Client) -> Self {
+        self.quotas_client = Some(quotas_client);
+        self
+    }
+
+    /// Sets the optional fields to request when describing services
+    pub fn with_service_include(mut self, service_include: Vec<ServiceField>) -> Self {
+        self.service_include = service_include;
+        self
+    }
+
+    /// Sets the optional fields to request when describing container instances
+    pub fn with_instance_include(mut self, instance_include: Vec<ContainerInstanceField>) -> Self {
+        self.instance_include = instance_include;
+        self
+    }
+
+    /// Checks whether the ECS API can be reached at all
+    ///
+    /// This issues the cheapest possible call. Any response from AWS, including an authorization
+    /// error, means the endpoint is reachable. Only timeouts and dispatch failures count as
+    /// unreachable, so this is distinct from the per-cluster scrape success.
+    async fn probe_reachability(&self) -> bool {
+        self.count_account_api_call("ListClusters");
+        let probe = self.client.list_clusters().max_results(1).send();
+        match tokio::time::timeout(REACHABILITY_PROBE_TIMEOUT, probe).await {
+            Ok(Ok(_)) => true,
+            Ok(Err(SdkError::ServiceError { .. })) | Ok(Err(SdkError::ResponseError { .. })) => {
+                true
+            }
+            Ok(Err(err)) => {
+                warn!("AWS reachability probe failed: {}", err);
+                false
+            }
+            Err(_) => {
+                warn!(
+                    "AWS reachability probe timed out after {:?}",
+                    REACHABILITY_PROBE_TIMEOUT
+                );
+                false
+            }
+        }
+    }
+
+    /// Returns the names of the resources and the number of pages it took to list them
+    async fn get_service_names(&self, cluster_name: &str) -> Result<(Vec<String>, usize)> {
+        let mut next_token = None;
+        let mut result = vec![];
+        let mut pages = 0;
+        loop {
+            pages += 1;
+            let request = self
+                .client(cluster_name)
+                .list_services()
+                .cluster(cluster_name)
+                .set_next_token(next_token);
+            let response = self
+                .with_retries("ListServices", cluster_name, || request.clone().send())
+                .await?;
+            if let Some(arn_vec) = response.service_arns {
+                result.extend(arn_vec)
+            }
+            next_token = response.next_token;
+            if next_token.is_none() {
+                break;
+            }
+        }
+        Ok((result, pages))
+    }
+
+    /// Returns the details of the given services
+    ///
+    /// This will only return an `Err` if the request itself fails.
+    /// In case of missing resources, it will only log the failures and the result will contain
+    /// those resources which were found.
+    async fn get_services_details(
+        &self,
+        cluster: &str,
+        service_names: Vec<&str>,
+    ) -> Result<Vec<aws_sdk_ecs::model::Service>> {
+        let semaphore = &self.semaphore(cluster);
+        let responses = try_join_all(service_names.chunks(self.services_chunk_size()).map(
+            |chunk| async move {
+                let _permits = self.acquire(semaphore).await?;
+                let request = self
+                    .client(cluster)
+                    .describe_services()
+                    .cluster(cluster)
+                    .set_services(Some(chunk.iter().map(|x| x.to_string()).collect()))
+                    .set_include(non_empty(&self.service_include()));
+                let response = self
+                    .with_retries("DescribeServices", cluster, || request.clone().send())
+                    .await?;
+                Ok::<_, color_eyre::Report>(response)
+            },
+        ))
+        .await?;
+
+        let mut result = vec![];
+        for response in responses {
+            log_failures(response.failures);
+            if let Some(s) = response.services {
+                result.extend(s);
+            }
+        }
+        Ok(result)
+    }
+
+    async fn get_service_metrics(
+        &self,
+        cluster: Cluster<'_>,
+        families: &ClusterMetricFamilies,
+        capacity_estimate: &mut CapacityEstimate,
+        task_definition_arns: &mut HashSet<String>,
+        max_service_tasks: &mut usize,
+    ) -> Result<usize> {
+        let ClusterMetricFamilies {
+            service_metric_family_desired,
+            service_metric_family_current,
+            service_missing_metric_family,
+            service_info_metric_family,
+            service_created_metric_family,
+            deployment_updated_metric_family,
+            image_metric_family,
+            last_deployment_metric_family,
+            execute_command_metric_family,
+            deployment_blocked_metric_family,
+            deployment_metric_family,
+            deployments_total_metric_family,
+            load_balancer_metric_family,
+            service_pagination_metrics: pagination_metrics,
+            target_health_metrics,
+            ..
+        } = families;
+        let (mut svc_list, list_pages) = self.get_service_names(cluster.name).await?;
+        svc_list.retain(|arn| {
+            is_service_selected(
+                arn,
+                self.service_name_include.as_ref(),
+                self.service_name_exclude.as_ref(),
+            )
+        });
+        pagination_metrics.set(
+            cluster,
+            list_pages,
+            chunk_count(svc_list.len(), self.services_chunk_size()),
+        );
+        let services = self
+            .get_services_details(cluster.name, svc_list.iter().map(String::as_ref).collect())
+            .await?;
+        let service_count = services.len();
+        // Target group ARNs of each service, by service name
+        let mut target_groups = HashMap::new();
+
+        for service in services {
+            let launch_type = service_launch_type(&service);
+            service_metric_family_desired
+                .with_label_values(
+                    &cluster.labels(&[service.service_name.as_ref().unwrap(), launch_type]),
+                )
+                .set(service.desired_count as i64);
+            *max_service_tasks = (*max_service_tasks).max(service.desired_count.max(0) as usize);
+            for (state, count) in [
+                ("running", service.running_count),
+                ("pending", service.pending_count),
+            ] {
+                service_metric_family_current
+                    .with_label_values(&cluster.labels(&[
+                        service.service_name.as_ref().unwrap(),
+                        state,
+                        launch_type,
+                    ]))
+                    .set(count as i64);
+            }
+            service_missing_metric_family
+                .with_label_values(&cluster.labels(&[service.service_name.as_ref().unwrap()]))
+                .set((service.desired_count - service.running_count).max(0) as i64);
+            let mut service_info_values = vec![
+                service.service_name.as_ref().unwrap().as_str(),
+                service
+                    .scheduling_strategy
+                    .as_ref()
+                    .map_or("unknown", SchedulingStrategy::as_str),
+                service
+                    .deployment_controller
+                    .as_ref()
+                    .and_then(|controller| controller.r#type.as_ref())
+                    .map_or("ECS", DeploymentControllerType::as_str),
+                launch_type,
+            ];
+            service_info_values.extend(self.tag_label_values(service.tags.as_deref()));
+            service_info_metric_family
+                .with_label_values(&cluster.labels(&service_info_values))
+                .set(1);
+
+            if let Some(created_at) = &service.created_at {
+                service_created_metric_family
+                    .with_label_values(&cluster.labels(&[service.service_name.as_ref().unwrap()]))
+                    .set(created_at.as_secs_f64());
+            }
+
+            execute_command_metric_family
+                .with_label_values(&cluster.labels(&[service.service_name.as_ref().unwrap()]))
+                .set(service.enable_execute_command as i64);
+
+            deployment_blocked_metric_family
+                .with_label_values(&cluster.labels(&[service.service_name.as_ref().unwrap()]))
+                .set(is_deployment_blocked(&service) as i64);
+
+            let deployments = service.deployments.as_deref().unwrap_or_default();
+            deployments_total_metric_family
+                .with_label_values(&cluster.labels(&[service.service_name.as_ref().unwrap()]))
+                .set(deployments.len() as i64);
+            for deployment in deployments {
+                deployment_metric_family
+                    .with_label_values(
+                        &cluster.labels(&[
+                            service.service_name.as_ref().unwrap(),
+                            deployment.id.as_deref().unwrap_or_default(),
+                            deployment
+                                .rollout_state
+                                .as_ref()
+                                .map_or("unknown", DeploymentRolloutState::as_str),
+                        ]),
+                    )
+                    .set(deployment.running_count as i64);
+                if let Some(updated_at) = &deployment.updated_at {
+                    deployment_updated_metric_family
+                        .with_label_values(&cluster.labels(&[
+                            service.service_name.as_ref().unwrap(),
+                            deployment.id.as_deref().unwrap_or_default(),
+                        ]))
+                        .set(updated_at.as_secs_f64());
+                }
+            }
+
+            for load_balancer in service.load_balancers.iter().flatten() {
+                let container_port = load_balancer
+                    .container_port
+                    .map(|port| port.to_string())
+                    .unwrap_or_default();
+                load_balancer_metric_family
+                    .with_label_values(
+                        &cluster.labels(&[
+                            service.service_name.as_ref().unwrap(),
+                            load_balancer
+                                .target_group_arn
+                                .as_deref()
+                                .unwrap_or_default(),
+                            load_balancer.container_name.as_deref().unwrap_or_default(),
+                            &container_port,
+                        ]),
+                    )
+                    .set(1);
+            }
+
+            if let Some(completed_at) = last_completed_deployment(&service) {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+                last_deployment_metric_family
+                    .with_label_values(&cluster.labels(&[service.service_name.as_ref().unwrap()]))
+                    .set(now - completed_at);
+            }
+
+            if self.with_capacity_shortfall {
+                self.add_capacity_demand(cluster, &service, capacity_estimate)
+                    .await;
+            }
+
+            if self.with_task_definitions {
+                task_definition_arns.extend(service.task_definition.clone());
+            }
+
+            if self.with_images {
+                if let Some(task_definition_arn) = &service.task_definition {
+                    self.set_image_metrics(cluster, task_definition_arn, image_metric_family)
+                        .await;
+                }
+            }
+
+            if self.elb_client.is_some() {
+                target_groups.insert(
+                    service.service_name.clone().unwrap_or_default(),
+                    service
+                        .load_balancers
+                        .iter()
+                        .flatten()
+                        .filter_map(|load_balancer| load_balancer.target_group_arn.clone())
+                        .collect::<Vec<_>>(),
+                );
+            }
+        }
+
+        if let Some(elb_client) = &self.elb_client {
+            self.set_target_health_metrics(
+                cluster,
+                elb_client,
+                &target_groups,
+                target_health_metrics,
+            )
+            .await;
+        }
+
+        Ok(service_count)
+    }
+
+    /// Sets the number of healthy and unhealthy targets of each service's target groups
+    ///
+    /// Target groups shared by several services are only described once. Failures are logged,
+    /// and the other target groups are still described.
+    async fn set_target_health_metrics(
+        &self,
+        cluster: Cluster<'_>,
+        elb_client: &aws_sdk_elasticloadbalancingv2::Client,
+        target_groups: &HashMap<String, Vec<String>>,
+        target_health_metrics: &TargetHealthMetrics,
+    ) {
+        let semaphore = &self.semaphore(cluster.name);
+        let target_group_arns: HashSet<&str> = target_groups
+            .values()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        let counts: HashMap<&str, target_health::TargetHealthCounts> = join_all(
+            target_group_arns
+                .into_iter()
+                .map(|target_group_arn| async move {
+                    let result = async {
+                        let _permits = self.acquire(semaphore).await?;
+                        let request = elb_client
+                            .describe_target_health()
+                            .target_group_arn(target_group_arn);
+                        let response = self
+                            .with_retries("DescribeTargetHealth", cluster.name, || {
+                                request.clone().send()
+                            })
+                            .await?;
+                        Ok::<_, color_eyre::Report>(target_health::count_target_health(response))
+                    }
+                    .await;
+                    match result {
+                        Ok(counts) => Some((target_group_arn, counts)),
+                        Err(err) => {
+                            warn!(
+                                "Failed to get health of target group `{}`: {}",
+                                target_group_arn, err
+                            );
+                            None
+                        }
+                    }
+                }),
+        )
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+
+        for (service_name, target_group_arns) in target_groups {
+            for target_group_arn in target_group_arns {
+                let counts = match counts.get(target_group_arn.as_str()) {
+                    Some(counts) => counts,
+                    None => continue,
+                };
+                let labels = cluster.labels(&[service_name.as_str(), target_group_arn.as_str()]);
+                target_health_metrics
+                    .healthy
+                    .with_label_values(&labels)
+                    .set(counts.healthy as i64);
+                target_health_metrics
+                    .unhealthy
+                    .with_label_values(&labels)
+                    .set(counts.unhealthy as i64);
+            }
+        }
+    }
+
+    /// Adds the resources needed by the service's missing tasks to the estimate
+    ///
+    /// Fargate services, including those on a Fargate capacity provider, don't use the instances'
+    /// resources, so they're ignored.
+    async fn add_capacity_demand(
+        &self,
+        cluster: Cluster<'_>,
+        service: &Service,
+        capacity_estimate: &mut CapacityEstimate,
+    ) {
+        let missing_tasks = (service.desired_count - service.running_count).max(0) as i64;
+        if missing_tasks == 0 || service_launch_type(service) == "FARGATE" {
+            return;
+        }
+        let task_definition_arn = match &service.task_definition {
+            Some(task_definition_arn) => task_definition_arn,
+            None => return,
+        };
+        match self
+            .get_task_definition(cluster.name, task_definition_arn)
+            .await
+        {
+            Ok(task_definition) => {
+                for (resource, value) in task_resources(&task_definition) {
+                    *capacity_estimate.demanded.entry(resource).or_default() +=
+                        value * missing_tasks;
+                }
+            }
+            Err(err) => warn!(
+                "Failed to describe task definition `{}`: {}",
+                task_definition_arn, err
+            ),
+        }
+    }
+
+    /// Returns the given task definition, describing it only if it isn't already known
+    async fn get_task_definition(
+        &self,
+        cluster: &str,
+        task_definition_arn: &str,
+    ) -> Result<TaskDefinition> {
+        if let Some(task_definition) = self
+            .task_definitions
+            .lock()
+            .unwrap()
+            .get(task_definition_arn)
+        {
+            return Ok(task_definition.clone());
+        }
+
+        let semaphore = self.semaphore(cluster);
+        let _permits = self.acquire(&semaphore).await?;
+        let request = self
+            .client(cluster)
+            .describe_task_definition()
+            .task_definition(task_definition_arn);
+        let response = self
+            .with_retries("DescribeTaskDefinition", cluster, || request.clone().send())
+            .await?;
+        let task_definition = response
+            .task_definition
+            .ok_or_else(|| eyre!("Task definition missing from the response"))?;
+        self.task_definitions
+            .lock()
+            .unwrap()
+            .insert(task_definition_arn.to_string(), task_definition.clone());
+        Ok(task_definition)
+    }
+
+    /// Sets the image info metric for each container of the task definition
+    ///
+    /// Failures are only logged, so that they don't fail the whole service scrape.
+    async fn set_image_metrics(
+        &self,
+        cluster: Cluster<'_>,
+        task_definition_arn: &str,
+        image_metric_family: &IntGaugeVec,
+    ) {
+        let task_definition = match self
+            .get_task_definition(cluster.name, task_definition_arn)
+            .await
+        {
+            Ok(task_definition) => task_definition,
+            Err(err) => {
+                warn!(
+                    "Failed to describe task definition `{}`: {}",
+                    task_definition_arn, err
+                );
+                return;
+            }
+        };
+        let family = task_definition.family.as_deref().unwrap_or_default();
+        for container in task_definition.container_definitions.iter().flatten() {
+            if let (Some(name), Some(image)) = (&container.name, &container.image) {
+                image_metric_family
+                    .with_label_values(&cluster.labels(&[family, name, image]))
+                    .set(1);
+            }
+        }
+    }
+
+    /// Sets the CPU and memory registered by each of the given task definitions
+    ///
+    /// Each one is only described once, as revisions are shared by services and never change.
+    async fn get_task_definition_metrics(
+        &self,
+        cluster: Cluster<'_>,
+        task_definition_arns: &HashSet<String>,
+        task_definition_cpu_metric_family: &IntGaugeVec,
+        task_definition_memory_metric_family: &IntGaugeVec,
+    ) -> Result<usize> {
+        for task_definition_arn in task_definition_arns {
+            let task_definition = self
+                .get_task_definition(cluster.name, task_definition_arn)
+                .await?;
+            let family = task_definition.family.as_deref().unwrap_or_default();
+            let revision = task_definition.revision.to_string();
+            let labels = cluster.labels(&[family, &revision]);
+            for (resource, value) in task_resources(&task_definition) {
+                let metric_family = match resource {
+                    "cpu" => task_definition_cpu_metric_family,
+                    _ => task_definition_memory_metric_family,
+                };
+                metric_family.with_label_values(&labels).set(value);
+            }
+        }
+        Ok(task_definition_arns.len())
+    }
+
+    /// Returns the names of the resources and the number of pages it took to list them
+    async fn get_container_instance_names(
+        &self,
+        cluster_name: &str,
+    ) -> Result<(Vec<String>, usize)> {
+        let mut next_token = None;
+        let mut result = vec![];
+        let mut pages = 0;
+        loop {
+            pages += 1;
+            let request = self
+                .client(cluster_name)
+                .list_container_instances()
+                .cluster(cluster_name)
+                .set_next_token(next_token);
+            let response = self
+                .with_retries("ListContainerInstances", cluster_name, || {
+                    request.clone().send()
+                })
+                .await?;
+            if let Some(arn_vec) = response.container_instance_arns {
+                result.extend(arn_vec)
+            }
+            next_token = response.next_token;
+            if next_token.is_none() {
+                break;
+            }
+        }
+        Ok((result, pages))
+    }
+
+    async fn get_container_instance_details(
+        &self,
+        cluster: &str,
+        instance_names: Vec<&str>,
+    ) -> Result<Vec<aws_sdk_ecs::model::ContainerInstance>> {
+        let semaphore = &self.semaphore(cluster);
+        let responses = try_join_all(instance_names.chunks(self.instances_chunk_size()).map(
+            |chunk| async move {
+                let _permits = self.acquire(semaphore).await?;
+                let request = self
+                    .client(cluster)
+                    .describe_container_instances()
+                    .cluster(cluster)
+                    .set_container_instances(Some(chunk.iter().map(|x| x.to_string()).collect()))
+                    .set_include(non_empty(&self.instance_include));
+                let response = self
+                    .with_retries("DescribeContainerInstances", cluster, || {
+                        request.clone().send()
+                    })
+                    .await?;
+                Ok::<_, color_eyre::Report>(response)
+            },
+        ))
+        .await?;
+
+        let mut result = vec![];
+        for response in responses {
+            log_failures(response.failures);
+            if let Some(s) = response.container_instances {
+                result.extend(s);
+            }
+        }
+        Ok(result)
+    }
+
+    async fn get_container_instance_metrics(
+        &self,
+        cluster: Cluster<'_>,
+        families: &ClusterMetricFamilies,
+        capacity_estimate: &mut CapacityEstimate,
+    ) -> Result<usize> {
+        let ClusterMetricFamilies {
+            task_metric_family,
+            resource_metric_family_registered,
+            resource_metric_family_remaining,
+            ports_metric_family,
+            instance_status_metric_family,
+            instance_status_info_metric_family,
+            instance_info_metric_family,
+            agent_connected_metric_family,
+            agent_info_metric_family,
+            attribute_metric_family,
+            instance_pagination_metrics: pagination_metrics,
+            ..
+        } = families;
+        let (instance_name_list, list_pages) =
+            self.get_container_instance_names(cluster.name).await?;
+        pagination_metrics.set(
+            cluster,
+            list_pages,
+            chunk_count(instance_name_list.len(), self.instances_chunk_size()),
+        );
+        let instances = self
+            .get_container_instance_details(
+                cluster.name,
+                instance_name_list.iter().map(String::as_ref).collect(),
+            )
+            .await?;
+        let instance_count = instances.len();
+
+        let mut instances_by_status: HashMap<&str, i64> = HashMap::new();
+        for instance in &instances {
+            *instances_by_status
+                .entry(instance.status.as_deref().unwrap_or("UNKNOWN"))
+                .or_default() += 1;
+        }
+        for (status, count) in instances_by_status {
+            instance_status_metric_family
+                .with_label_values(&cluster.labels(&[status]))
+                .set(count);
+        }
+
+        for counted_attribute in &self.counted_attributes {
+            let count = instances
+                .iter()
+                .filter(|instance| {
+                    instance.status.as_deref() == Some("ACTIVE") && instance.agent_connected
+                })
+                .filter(|instance| has_attribute(instance, counted_attribute))
+                .count();
+            attribute_metric_family
+                .with_label_values(&cluster.labels(&[counted_attribute]))
+                .set(count as i64);
+        }
+
+        for instance in &instances {
+            let instance_label_values =
+                match instance_label_values(instance, self.instance_ip_label) {
+                    Some(instance_label_values) => instance_label_values,
+                    None => {
+                        warn!(
+                            container_instance_arn = instance.container_instance_arn.as_deref(),
+                            "Skipping container instance without any identifier"
+                        );
+                        continue;
+                    }
+                };
+
+            let status = instance.status.as_deref().unwrap_or("UNKNOWN");
+            let instance_status_values = with_value(&instance_label_values, status);
+            instance_status_info_metric_family
+                .with_label_values(&cluster.labels(&instance_status_values))
+                .set(1);
+
+            let mut instance_info_values = instance_label_values.clone();
+            for name in ["ecs.availability-zone", "ecs.instance-type", "ecs.ami-id"] {
+                instance_info_values.push(attribute_value(instance, name).unwrap_or("unknown"));
+            }
+            instance_info_metric_family
+                .with_label_values(&cluster.labels(&instance_info_values))
+                .set(1);
+
+            agent_connected_metric_family
+                .with_label_values(&cluster.labels(&instance_label_values))
+                .set(instance.agent_connected as i64);
+            let version_info = instance.version_info.as_ref();
+            let mut agent_info_values = instance_label_values.clone();
+            agent_info_values.push(
+                version_info
+                    .and_then(|version_info| version_info.agent_version.as_deref())
+                    .unwrap_or("unknown"),
+            );
+            agent_info_values.push(
+                version_info
+                    .and_then(|version_info| version_info.docker_version.as_deref())
+                    .unwrap_or("unknown"),
+            );
+            agent_info_metric_family
+                .with_label_values(&cluster.labels(&agent_info_values))
+                .set(1);
+
+            task_metric_family
+                .with_label_values(&cluster.labels(&with_value(&instance_status_values, "running")))
+                .set(instance.running_tasks_count as i64);
+            task_metric_family
+                .with_label_values(&cluster.labels(&with_value(&instance_status_values, "pending")))
+                .set(instance.pending_tasks_count as i64);
+
+            if let Some(remaining_resources) = &instance.remaining_resources {
+                let resources: Vec<(&str, i64)> = remaining_resources
+                    .iter()
+                    .filter_map(filter_resources)
+                    .collect();
+                let schedulable =
+                    instance.status.as_deref() == Some("ACTIVE") && instance.agent_connected;
+                for resource in resources {
+                    resource_metric_family_remaining
+                        .with_label_values(
+                            &cluster.labels(&with_value(&instance_label_values, resource.0)),
+                        )
+                        .set(resource.1);
+                    if schedulable {
+                        *capacity_estimate.remaining.entry(resource.0).or_default() += resource.1;
+                    }
+                }
+                for (protocol, port_count) in remaining_resources.iter().filter_map(reserved_ports)
+                {
+                    ports_metric_family
+                        .with_label_values(
+                            &cluster.labels(&with_value(&instance_label_values, protocol)),
+                        )
+                        .set(port_count);
+                }
+            }
+
+            let registered_resources = instance
+                .registered_resources
+                .as_ref()
+                .filter(|_| !self.omit_registered_resources);
+            if let Some(registered_resources) = registered_resources {
+                let resources: Vec<(&str, i64)> = registered_resources
+                    .iter()
+                    .filter_map(filter_resources)
+                    .collect();
+                for resource in resources {
+                    resource_metric_family_registered
+                        .with_label_values(
+                            &cluster.labels(&with_value(&instance_label_values, resource.0)),
+                        )
+                        .set(resource.1);
+                }
+            }
+        }
+
+        Ok(instance_count)
+    }
+
+    async fn get_task_names(&self, cluster_name: &str) -> Result<Vec<String>> {
+        let mut next_token = None;
+        let mut result = vec![];
+        loop {
+            let request = self
+                .client(cluster_name)
+                .list_tasks()
+                .cluster(cluster_name)
+                .set_next_token(next_token);
+            let response = self
+                .with_retries("ListTasks", cluster_name, || request.clone().send())
+                .await?;
+            if let Some(arn_vec) = response.task_arns {
+                result.extend(arn_vec)
+            }
+            next_token = response.next_token;
+            if next_token.is_none() {
+                break;
+            }
+        }
+        Ok(result)
+    }
+
+    /// Returns at most `limit` of the tasks which were stopped, most recent ones first
+    async fn get_stopped_task_names(
+        &self,
+        cluster_name: &str,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        let mut next_token = None;
+        let mut result = vec![];
+        while result.len() < limit {
+            let request = self
+                .client(cluster_name)
+                .list_tasks()
+                .cluster(cluster_name)
+                .desired_status(DesiredStatus::Stopped)
+                .max_results((limit - result.len()).min(100) as i32)
+                .set_next_token(next_token);
+            let response = self
+                .with_retries("ListTasks", cluster_name, || request.clone().send())
+                .await?;
+            if let Some(arn_vec) = response.task_arns {
+                result.extend(arn_vec)
+            }
+            next_token = response.next_token;
+            if next_token.is_none() {
+                break;
+            }
+        }
+        result.truncate(limit);
+        Ok(result)
+    }
+
+    async fn get_task_details(&self, cluster: &str, task_names: Vec<&str>) -> Result<Vec<Task>> {
+        let semaphore = &self.semaphore(cluster);
+        let responses = try_join_all(task_names.chunks(100).map(|chunk| async move {
+            let _permits = self.acquire(semaphore).await?;
+            let request = self
+                .client(cluster)
+                .describe_tasks()
+                .cluster(cluster)
+                .set_tasks(Some(chunk.iter().map(|x| x.to_string()).collect()));
+            let response = self
+                .with_retries("DescribeTasks", cluster, || request.clone().send())
+                .await?;
+            Ok::<_, color_eyre::Report>(response)
+        }))
+        .await?;
+
+        let mut result = vec![];
+        for response in responses {
+            log_failures(response.failures);
+            if let Some(t) = response.tasks {
+                result.extend(t);
+            }
+        }
+        Ok(result)
+    }
+
+    async fn get_task_metrics(
+        &self,
+        cluster: Cluster<'_>,
+        task_info_metric_family: &IntGaugeVec,
+        tasks_total_metric_family: &IntGaugeVec,
+        managed_agent_metric_family: &IntGaugeVec,
+    ) -> Result<usize> {
+        let task_name_list = self.get_task_names(cluster.name).await?;
+        let tasks = self
+            .get_task_details(
+                cluster.name,
+                task_name_list.iter().map(String::as_ref).collect(),
+            )
+            .await?;
+
+        if self.with_tasks {
+            let mut tasks_by_status: HashMap<&str, i64> = HashMap::new();
+            for task in &tasks {
+                let last_status = task.last_status.as_deref().unwrap_or("UNKNOWN");
+                *tasks_by_status.entry(last_status).or_default() += 1;
+                let service_name = task
+                    .group
+                    .as_deref()
+                    .and_then(|group| group.strip_prefix("service:"))
+                    .unwrap_or_default();
+                task_info_metric_family
+                    .with_label_values(&cluster.labels(&[
+                        task.task_arn.as_deref().unwrap_or_default(),
+                        service_name,
+                        last_status,
+                        task.desired_status.as_deref().unwrap_or("UNKNOWN"),
+                        task.launch_type.as_ref().map_or("", LaunchType::as_str),
+                    ]))
+                    .set(1);
+            }
+            for (status, count) in tasks_by_status {
+                tasks_total_metric_family
+                    .with_label_values(&cluster.labels(&[status]))
+                    .set(count);
+            }
+        }
+
+        for task in tasks.iter().filter(|_| self.with_managed_agents) {
+            let task_arn = task.task_arn.as_deref().unwrap_or_default();
+            let managed_agents = task
+                .containers
+                .iter()
+                .flatten()
+                .flat_map(|container| container.managed_agents.iter().flatten());
+            for agent in managed_agents {
+                if let (Some(name), Some(status)) = (&agent.name, &agent.last_status) {
+                    managed_agent_metric_family
+                        .with_label_values(&cluster.labels(&[task_arn, name.as_str(), status]))
+                        .set(1);
+                }
+            }
+        }
+
+        Ok(tasks.len())
+    }
+
+    async fn get_stopped_task_metrics(
+        &self,
+        cluster: Cluster<'_>,
+        max_stopped_tasks: usize,
+        stopped_tasks_metric_family: &IntGaugeVec,
+    ) -> Result<usize> {
+        let task_name_list = self
+            .get_stopped_task_names(cluster.name, max_stopped_tasks)
+            .await?;
+        let tasks = self
+            .get_task_details(
+                cluster.name,
+                task_name_list.iter().map(String::as_ref).collect(),
+            )
+            .await?;
+
+        let mut tasks_by_reason: HashMap<(&str, &str), i64> = HashMap::new();
+        for task in &tasks {
+            let service_name = task
+                .group
+                .as_deref()
+                .and_then(|group| group.strip_prefix("service:"))
+                .unwrap_or_default();
+            *tasks_by_reason
+                .entry((service_name, stopped_reason_bucket(task)))
+                .or_default() += 1;
+        }
+        for ((service_name, stopped_reason), count) in tasks_by_reason {
+            stopped_tasks_metric_family
+                .with_label_values(&cluster.labels(&[service_name, stopped_reason]))
+                .set(count);
+        }
+
+        Ok(tasks.len())
+    }
+
+    /// Publishes the account's ECS quotas and returns them for computing usage ratios
+    ///
+    /// Failures are only logged, as quotas are informative and shouldn't fail the scrape.
+    async fn get_quota_metrics(
+        &self,
+        quotas_client: &aws_sdk_servicequotas::Client,
+        registry: &Registry,
+    ) -> HashMap<String, f64> {
+        let quota_metric_family = register_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("service_quota"),
+                "Applied ECS service quotas"
+            ),
+            &["quota_name"],
+            registry
+        )
+        .expect("Failed to generate aws_ecs_service_quota metric family");
+
+        match quotas::get_ecs_quotas(quotas_client).await {
+            Ok(quotas) => {
+                for (quota_name, value) in &quotas {
+                    quota_metric_family
+                        .with_label_values(&[quota_name])
+                        .set(*value);
+                }
+                quotas
+            }
+            Err(err) => {
+                warn!("Failed to get ECS service quotas: {}", err);
+                HashMap::new()
+            }
+        }
+    }
+
+    /// Scrapes all the resources of a single cluster
+    ///
+    /// Failures are only logged and reflected in the scrape success metric, so that they don't
+    /// affect the other clusters.
+    async fn scrape_cluster(
+        &self,
+        cluster: Cluster<'_>,
+        families: &ClusterMetricFamilies,
+        quotas: &HashMap<String, f64>,
+    ) {
+        let ClusterMetricFamilies {
+            scrape_metric,
+            attribute_metric_family,
+            cluster_info_metric_family,
+            task_definition_cpu_metric_family,
+            task_definition_memory_metric_family,
+            managed_agent_metric_family,
+            task_info_metric_family,
+            tasks_total_metric_family,
+            stopped_tasks_metric_family,
+            service_pagination_metrics,
+            instance_pagination_metrics,
+            capacity_provider_metric_family,
+            default_capacity_provider_metric_family,
+            capacity_shortfall_metric_family,
+            quota_usage_metric_family,
+            scrape_duration_metric_family,
+            cluster_stats_metrics,
+            ..
+        } = families;
+        let cluster_details = if self.is_enabled(Collector::Cluster) {
+            self.scrape_resource(
+                cluster,
+                families,
+                "cluster_stats",
+                self.get_cluster_details(cluster.name),
+            )
+            .await
+        } else {
+            None
+        };
+
+        // Whether every resource kind was successfully scraped and returned no objects, which is
+        // only meaningful if some of them were scraped
+        let mut is_empty = true;
+        let mut emptiness_measured = false;
+        // The estimate is only meaningful if both instances and services were scraped
+        let mut capacity_estimate = CapacityEstimate::default();
+        let mut capacity_complete = true;
+        let mut task_definition_arns = HashSet::new();
+        // Desired tasks of the largest service, which is the closest to the quota of tasks per service
+        let mut max_service_tasks = 0;
+
+        if self.is_enabled(Collector::Instances) {
+            emptiness_measured = true;
+            let instance_count = self
+                .scrape_resource(
+                    cluster,
+                    families,
+                    "cluster_instances",
+                    self.get_container_instance_metrics(cluster, families, &mut capacity_estimate),
+                )
+                .await;
+            match instance_count {
+                Some(instance_count) => {
+                    is_empty &= instance_count == 0;
+                    set_quota_usage(
+                        quota_usage_metric_family,
+                        quotas,
+                        cluster,
+                        quotas::INSTANCES_PER_CLUSTER,
+                        instance_count,
+                    );
+                }
+                None => {
+                    is_empty = false;
+                    capacity_complete = false;
+                }
+            }
+        } else {
+            capacity_complete = false;
+        }
+
+        if self.is_enabled(Collector::Services) {
+            emptiness_measured = true;
+            let service_count = self
+                .scrape_resource(
+                    cluster,
+                    families,
+                    "services",
+                    self.get_service_metrics(
+                        cluster,
+                        families,
+                        &mut capacity_estimate,
+                        &mut task_definition_arns,
+                        &mut max_service_tasks,
+                    ),
+                )
+                .await;
+            match service_count {
+                Some(service_count) => {
+                    is_empty &= service_count == 0;
+                    set_quota_usage(
+                        quota_usage_metric_family,
+                        quotas,
+                        cluster,
+                        quotas::SERVICES_PER_CLUSTER,
+                        service_count,
+                    );
+                    set_quota_usage(
+                        quota_usage_metric_family,
+                        quotas,
+                        cluster,
+                        quotas::TASKS_PER_SERVICE,
+                        max_service_tasks,
+                    );
+                }
+                None => {
+                    is_empty = false;
+                    capacity_complete = false;
+                }
+            }
+        } else {
+            capacity_complete = false;
+        }
+
+        // Only the task definitions of the services which could be described are known
+        if self.with_task_definitions && !task_definition_arns.is_empty() {
+            self.scrape_resource(
+                cluster,
+                families,
+                "task_definitions",
+                self.get_task_definition_metrics(
+                    cluster,
+                    &task_definition_arns,
+                    task_definition_cpu_metric_family,
+                    task_definition_memory_metric_family,
+                ),
+            )
+            .await;
+        }
+
+        if self.is_enabled(Collector::Tasks) && (self.with_tasks || self.with_managed_agents) {
+            emptiness_measured = true;
+            let task_count = self
+                .scrape_resource(
+                    cluster,
+                    families,
+                    "cluster_tasks",
+                    self.get_task_metrics(
+                        cluster,
+                        task_info_metric_family,
+                        tasks_total_metric_family,
+                        managed_agent_metric_family,
+                    ),
+                )
+                .await;
+            is_empty &= task_count == Some(0);
+        }
+
+        if let Some(max_stopped_tasks) = self
+            .max_stopped_tasks
+            .filter(|_| self.is_enabled(Collector::Tasks))
+        {
+            emptiness_measured = true;
+            let task_count = self
+                .scrape_resource(
+                    cluster,
+                    families,
+                    "stopped_tasks",
+                    self.get_stopped_task_metrics(
+                        cluster,
+                        max_stopped_tasks,
+                        stopped_tasks_metric_family,
+                    ),
+                )
+                .await;
+            is_empty &= task_count == Some(0);
+        }
+
+        let hidden = self.hide_empty_clusters && emptiness_measured && is_empty;
+        if let Some(capacity_shortfall_metric_family) = &capacity_shortfall_metric_family {
+            if capacity_complete && !hidden {
+                capacity_shortfall_metric_family
+                    .with_label_values(&cluster.labels(&[]))
+                    .set(capacity_estimate.is_short() as i64);
+            }
+        }
+        if let Some(cluster_details) = cluster_details.as_ref().filter(|_| !hidden) {
+            cluster_stats_metrics.set(cluster, cluster_details);
+            cluster_info_metric_family
+                .with_label_values(
+                    &cluster.labels(&self.tag_label_values(cluster_details.tags.as_deref())),
+                )
+                .set(1);
+            set_capacity_provider_metrics(
+                cluster,
+                cluster_details,
+                capacity_provider_metric_family,
+                default_capacity_provider_metric_family,
+            );
+        }
+
+        if hidden {
+            for scraped_resource in SCRAPED_RESOURCES {
+                let _ = scrape_metric.remove_label_values(&cluster.labels(&[scraped_resource]));
+                let _ = scrape_duration_metric_family
+                    .remove_label_values(&cluster.labels(&[scraped_resource]));
+            }
+            for counted_attribute in &self.counted_attributes {
+                let _ = attribute_metric_family
+                    .remove_label_values(&cluster.labels(&[counted_attribute]));
+            }
+            for quota_name in [
+                quotas::INSTANCES_PER_CLUSTER,
+                quotas::SERVICES_PER_CLUSTER,
+                quotas::TASKS_PER_SERVICE,
+            ] {
+                let _ =
+                    quota_usage_metric_family.remove_label_values(&cluster.labels(&[quota_name]));
+            }
+            service_pagination_metrics.remove(cluster);
+            instance_pagination_metrics.remove(cluster);
+        }
+    }
+
+    /// Scrapes one kind of resource of the cluster, recording how long it took and whether it succeeded
+    ///
+    /// Returns `None` if the scrape failed or timed out, which has already been logged.
+    async fn scrape_resource<T>(
+        &self,
+        cluster: Cluster<'_>,
+        families: &ClusterMetricFamilies,
+        scraped_resource: &str,
+        scrape: impl Future<Output = Result<T>>,
+    ) -> Option<T> {
+        let labels = cluster.labels(&[scraped_resource]);
+        let timer = families
+            .scrape_duration_metric_family
+            .with_label_values(&labels)
+            .start_timer();
+        let result = with_timeout(self.scrape_timeout, scrape).await;
+        timer.observe_duration();
+        let scrape_metric = families.scrape_metric.with_label_values(&labels);
+        match result {
+            Ok(value) => {
+                scrape_metric.set(1);
+                Some(value)
+            }
+            Err(err) => {
+                scrape_metric.set(0);
+                let error_kind = self.count_scrape_error(cluster, scraped_resource, &err);
+                warn!(
+                    error_kind,
+                    "Failed to get `{}` metrics for cluster `{}`: {}",
+                    scraped_resource,
+                    cluster.name,
+                    err
+                );
+                None
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Scraper for EcsClient {
+    async fn scrape(&self) -> Result<Registry> {
+        let registry = Registry::new();
+        registry
+            .register(Box::new(self.throttled_metric_family.clone()))
+            .expect("Failed to register aws_ecs_api_throttled_total metric family");
+        registry
+            .register(Box::new(self.api_calls_metric_family.clone()))
+            .expect("Failed to register aws_ecs_exporter_api_calls_total metric family");
+        registry
+            .register(Box::new(self.account_api_calls_metric_family.clone()))
+            .expect("Failed to register aws_ecs_exporter_account_api_calls_total metric family");
+        registry
+            .register(Box::new(self.scrape_errors_metric_family()))
+            .expect("Failed to register aws_ecs_cluster_scrape_errors_total metric family");
+        let scrape_metric = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("cluster_scrape_success"),
+                "Whether the scrape for a particular cluster and resource kind was successful"
+            ),
+            &self.cluster_label_names(&["scraped_resource"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_cluster_scrape_success metric");
+
+        let reachable_metric = register_int_gauge_with_registry!(
+            opts!(
+                self.metric_name("aws_reachable"),
+                "Whether the ECS API endpoint could be reached during this scrape"
+            ),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_aws_reachable metric");
+        reachable_metric.set(self.probe_reachability().await as i64);
+
+        let task_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("instance_tasks_total"),
+                "Tasks running on the Container Instances (ec2)"
+            ),
+            &self.cluster_label_names(&self.instance_label_names(&["status", "state"])),
+            registry
+        )
+        .expect("Failed to register aws_ecs_instance_tasks metric family");
+
+        let resource_metric_family_registered = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("instance_resources_registered"),
+                "Initial resources available on ECS Container Instance"
+            ),
+            &self.cluster_label_names(&self.instance_label_names(&["resource"])),
+            registry
+        )
+        .expect("Failed to register aws_ecs_instance_resources_registered metric family");
+
+        let resource_metric_family_remaining = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("instance_resources_remaining"),
+                "Initial resources available on ECS Container Instance"
+            ),
+            &self.cluster_label_names(&self.instance_label_names(&["resource"])),
+            registry
+        )
+        .expect("Failed to register aws_ecs_instance_resources_remaining metric family");
+
+        let ports_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("instance_ports_reserved"),
+                "Number of ports of the ECS Container Instance reserved by the agent and the tasks"
+            ),
+            &self.cluster_label_names(&self.instance_label_names(&["protocol"])),
+            registry
+        )
+        .expect("Failed to register aws_ecs_instance_ports_reserved metric family");
+
+        let instance_status_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("cluster_instances_by_status"),
+                "Number of ECS Container Instances in the cluster by status"
+            ),
+            &self.cluster_label_names(&["status"]),
+            registry
+        )
+        .expect("Failed to register aws_ecs_cluster_instances_by_status metric family");
+
+        let instance_status_info_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("instance_status"),
+                "Status of the ECS Container Instance"
+            ),
+            &self.cluster_label_names(&self.instance_label_names(&["status"])),
+            registry
+        )
+        .expect("Failed to register aws_ecs_instance_status metric family");
+
+        let instance_info_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("instance_info"),
+                "Placement and AMI of the ECS Container Instance"
+            ),
+            &self.cluster_label_names(&self.instance_label_names(&[
+                "availability_zone",
+                "instance_type",
+                "ami_id"
+            ])),
+            registry
+        )
+        .expect("Failed to register aws_ecs_instance_info metric family");
+
+        let agent_connected_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("instance_agent_connected"),
+                "Whether the ECS agent of the Container Instance is connected"
+            ),
+            &self.cluster_label_names(&self.instance_label_names(&[])),
+            registry
+        )
+        .expect("Failed to register aws_ecs_instance_agent_connected metric family");
+
+        let agent_info_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("instance_agent_info"),
+                "Versions of the ECS agent and Docker running on the Container Instance"
+            ),
+            &self.cluster_label_names(
+                &self.instance_label_names(&["agent_version", "docker_version"])
+            ),
+            registry
+        )
+        .expect("Failed to register aws_ecs_instance_agent_info metric family");
+
+        let attribute_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("cluster_instances_matching_attribute"),
+                "Number of active and connected ECS Container Instances having the attribute"
+            ),
+            &self.cluster_label_names(&["attribute"]),
+            registry
+        )
+        .expect("Failed to register aws_ecs_cluster_instances_matching_attribute metric family");
+
+        let service_metric_family_current = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("service_current_total"),
+                "Current Number of ECS Services"
+            ),
+            &self.cluster_label_names(&["service_name", "state", "launch_type"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_service metric family");
+
+        let service_metric_family_desired = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("service_desired"),
+                "Desired Number of ECS Services"
+            ),
+            &self.cluster_label_names(&["service_name", "launch_type"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_service metric family");
+
+        let service_missing_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("service_missing"),
+                "Number of tasks the ECS Service is missing to reach its desired count"
+            ),
+            &self.cluster_label_names(&["service_name"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_service_missing metric family");
+
+        let service_info_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("service_info"),
+                "How the ECS Service is scheduled and deployed. The desired count of DAEMON services follows the number of instances"
+            ),
+            &self.cluster_label_names(&self.tag_label_names(&[
+                "service_name",
+                "scheduling_strategy",
+                "deployment_controller",
+                "launch_type"
+            ])),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_service_info metric family");
+
+        let cluster_info_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("cluster_info"),
+                "ECS Cluster, with the tags given to be exported as labels"
+            ),
+            &self.cluster_label_names(&self.tag_label_names(&[])),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_cluster_info metric family");
+
+        let service_created_metric_family = register_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("service_created_timestamp_seconds"),
+                "When the ECS Service was created, as a Unix timestamp"
+            ),
+            &self.cluster_label_names(&["service_name"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_service_created_timestamp_seconds metric family");
+
+        let deployment_updated_metric_family = register_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("service_deployment_updated_timestamp_seconds"),
+                "When the deployment of the ECS Service was last updated, as a Unix timestamp"
+            ),
+            &self.cluster_label_names(&["service_name", "deployment_id"]),
+            registry
+        )
+        .expect(
+            "Failed to generate aws_ecs_service_deployment_updated_timestamp_seconds metric family",
+        );
+
+        let last_deployment_metric_family = register_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("service_time_since_last_deployment_seconds"),
+                "Time since the most recent completed deployment of the ECS Service"
+            ),
+            &self.cluster_label_names(&["service_name"]),
+            registry
+        )
+        .expect(
+            "Failed to generate aws_ecs_service_time_since_last_deployment_seconds metric family",
+        );
+
+        let execute_command_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("service_execute_command_enabled"),
+                "Whether ECS Exec is enabled for the ECS Service"
+            ),
+            &self.cluster_label_names(&["service_name"]),
+            registry
         )
-        .expect("Failed to generate aws_ecs_service metric family");
+        .expect("Failed to generate aws_ecs_service_execute_command_enabled metric family");
 
-        for cluster_name in &self.cluster_names {
-            let instance_scrape_metric =
-                scrape_metric.with_label_values(&[cluster_name, "cluster_instances"]);
-            match self
-                .get_container_instance_metrics(
-                    cluster_name,
-                    &task_metric_family,
-                    &resource_metric_family_registered,
-                    &resource_metric_family_remaining,
-                )
-                .await
-            {
-                Ok(()) => {
-                    instance_scrape_metric.set(1);
-                }
-                Err(err) => {
-                    warn!(
-                        "Failed to get instance metrics for cluster `{}`: {}",
-                        cluster_name, err
-                    );
-                }
-            }
+        let deployment_blocked_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("service_deployment_blocked"),
+                "Whether the newest deployment of the ECS Service was stopped by the circuit breaker"
+            ),
+            &self.cluster_label_names(&["service_name"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_service_deployment_blocked metric family");
 
-            let service_scrape_metric =
-                scrape_metric.with_label_values(&[cluster_name, "services"]);
-            match self
-                .get_service_metrics(
-                    cluster_name,
-                    &service_metric_family_desired,
-                    &service_metric_family_current,
-                )
-                .await
-            {
-                Ok(()) => {
-                    service_scrape_metric.set(1);
+        let deployment_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("service_deployment"),
+                "Number of running tasks of each deployment of the ECS Service"
+            ),
+            &self.cluster_label_names(&["service_name", "deployment_id", "rollout_state"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_service_deployment metric family");
+
+        let deployments_total_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("service_deployments_total"),
+                "Number of deployments of the ECS Service"
+            ),
+            &self.cluster_label_names(&["service_name"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_service_deployments_total metric family");
+
+        let load_balancer_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("service_load_balancer"),
+                "Target group the container of the ECS Service is registered with"
+            ),
+            &self.cluster_label_names(&[
+                "service_name",
+                "target_group_arn",
+                "container_name",
+                "container_port"
+            ]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_service_load_balancer metric family");
+
+        let image_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("task_definition_image_info"),
+                "Container images referenced by the services' task definitions"
+            ),
+            &self.cluster_label_names(&["task_definition_family", "container_name", "image"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_task_definition_image_info metric family");
+
+        let task_definition_cpu_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("task_definition_cpu"),
+                "CPU units registered by the services' task definitions"
+            ),
+            &self.cluster_label_names(&["family", "revision"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_task_definition_cpu metric family");
+
+        let task_definition_memory_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("task_definition_memory"),
+                "Memory, in MiB, registered by the services' task definitions"
+            ),
+            &self.cluster_label_names(&["family", "revision"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_task_definition_memory metric family");
+
+        let managed_agent_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("task_managed_agent_status"),
+                "Last status of the managed agents running in the tasks"
+            ),
+            &self.cluster_label_names(&["task_arn", "agent_name", "status"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_task_managed_agent_status metric family");
+
+        let task_info_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("task_info"),
+                "Tasks running in the cluster"
+            ),
+            &self.cluster_label_names(&[
+                "task_arn",
+                "service_name",
+                "last_status",
+                "desired_status",
+                "launch_type"
+            ]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_task_info metric family");
+
+        let tasks_total_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("tasks_total"),
+                "Number of tasks in the cluster by last status"
+            ),
+            &self.cluster_label_names(&["last_status"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_tasks_total metric family");
+
+        let stopped_tasks_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("stopped_tasks_total"),
+                "Number of recently stopped tasks in the cluster by service and reason"
+            ),
+            &self.cluster_label_names(&["service_name", "stopped_reason"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_stopped_tasks_total metric family");
+
+        let service_pagination_metrics = PaginationMetrics {
+            list_pages: register_int_gauge_vec_with_registry!(
+                opts!(
+                    self.metric_name("service_list_pages"),
+                    "Number of pages needed to list the ECS Services"
+                ),
+                &self.cluster_label_names(&[]),
+                registry
+            )
+            .expect("Failed to generate aws_ecs_service_list_pages metric family"),
+            describe_chunks: register_int_gauge_vec_with_registry!(
+                opts!(
+                    self.metric_name("service_describe_chunks"),
+                    "Number of calls needed to describe the ECS Services"
+                ),
+                &self.cluster_label_names(&[]),
+                registry
+            )
+            .expect("Failed to generate aws_ecs_service_describe_chunks metric family"),
+        };
+
+        let target_health_metrics = TargetHealthMetrics {
+            healthy: register_int_gauge_vec_with_registry!(
+                opts!(
+                    self.metric_name("service_target_healthy"),
+                    "Number of healthy targets in the ECS Service's target group"
+                ),
+                &self.cluster_label_names(&["service_name", "target_group_arn"]),
+                registry
+            )
+            .expect("Failed to generate aws_ecs_service_target_healthy metric family"),
+            unhealthy: register_int_gauge_vec_with_registry!(
+                opts!(
+                    self.metric_name("service_target_unhealthy"),
+                    "Number of unhealthy targets in the ECS Service's target group"
+                ),
+                &self.cluster_label_names(&["service_name", "target_group_arn"]),
+                registry
+            )
+            .expect("Failed to generate aws_ecs_service_target_unhealthy metric family"),
+        };
+
+        let instance_pagination_metrics = PaginationMetrics {
+            list_pages: register_int_gauge_vec_with_registry!(
+                opts!(
+                    self.metric_name("instance_list_pages"),
+                    "Number of pages needed to list the ECS Container Instances"
+                ),
+                &self.cluster_label_names(&[]),
+                registry
+            )
+            .expect("Failed to generate aws_ecs_instance_list_pages metric family"),
+            describe_chunks: register_int_gauge_vec_with_registry!(
+                opts!(
+                    self.metric_name("instance_describe_chunks"),
+                    "Number of calls needed to describe the ECS Container Instances"
+                ),
+                &self.cluster_label_names(&[]),
+                registry
+            )
+            .expect("Failed to generate aws_ecs_instance_describe_chunks metric family"),
+        };
+
+        let capacity_provider_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("cluster_capacity_providers"),
+                "Number of capacity providers associated with the cluster"
+            ),
+            &self.cluster_label_names(&[]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_cluster_capacity_providers metric family");
+
+        let default_capacity_provider_metric_family = register_int_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("cluster_default_capacity_provider_info"),
+                "Default capacity provider strategy of the cluster"
+            ),
+            &self.cluster_label_names(&["capacity_provider", "weight", "base"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_cluster_default_capacity_provider_info metric family");
+
+        let capacity_shortfall_metric_family = self.with_capacity_shortfall.then(|| {
+            register_int_gauge_vec_with_registry!(
+                opts!(
+                    self.metric_name("cluster_capacity_shortfall"),
+                    "Heuristic: whether the remaining instance resources can't fit the services' missing tasks"
+                ),
+                &self.cluster_label_names(&[]),
+                registry
+            )
+            .expect("Failed to generate aws_ecs_cluster_capacity_shortfall metric family")
+        });
+
+        let quotas = match &self.quotas_client {
+            Some(quotas_client) => self.get_quota_metrics(quotas_client, &registry).await,
+            None => HashMap::new(),
+        };
+        let quota_usage_metric_family = register_gauge_vec_with_registry!(
+            opts!(
+                self.metric_name("service_quota_usage_ratio"),
+                "Ratio of the observed resource count to the applied ECS quota, that of the service with the most desired tasks for tasks per service"
+            ),
+            &self.cluster_label_names(&["quota_name"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_service_quota_usage_ratio metric family");
+
+        if self.discover_clusters {
+            let discovered = match self.list_cluster_names().await {
+                Ok(cluster_names) if !self.cluster_tags.is_empty() => {
+                    self.filter_clusters_by_tags(cluster_names).await
                 }
+                result => result,
+            };
+            match discovered {
+                Ok(cluster_names) => self.clusters.replace(cluster_names),
                 Err(err) => warn!(
-                    "Failed to get service metrics for cluster `{}`: {}",
-                    cluster_name, err
+                    "Failed to list clusters, keeping the previous ones: {}",
+                    err
                 ),
             }
         }
+        // Work on a single snapshot, so that replacing the clusters doesn't affect this scrape
+        let cluster_names = self.clusters.snapshot();
+
+        let cluster_arns = if self.emit_cluster_arn {
+            self.get_cluster_arns(&cluster_names).await
+        } else {
+            HashMap::new()
+        };
+
+        let scrape_duration_metric_family = register_histogram_vec_with_registry!(
+            histogram_opts!(
+                self.metric_name("scrape_duration_seconds"),
+                "Time taken to scrape each kind of resource of the cluster",
+                SCRAPE_DURATION_BUCKETS.to_vec()
+            ),
+            &self.cluster_label_names(&["scraped_resource"]),
+            registry
+        )
+        .expect("Failed to generate aws_ecs_scrape_duration_seconds metric family");
+
+        let cluster_stats_metrics = ClusterStatsMetrics {
+            registered_instances: register_int_gauge_vec_with_registry!(
+                opts!(
+                    self.metric_name("cluster_registered_container_instances"),
+                    "Container instances registered in the cluster"
+                ),
+                &self.cluster_label_names(&[]),
+                registry
+            )
+            .expect(
+                "Failed to generate aws_ecs_cluster_registered_container_instances metric family",
+            ),
+            active_services: register_int_gauge_vec_with_registry!(
+                opts!(
+                    self.metric_name("cluster_active_services"),
+                    "Services in the ACTIVE state in the cluster"
+                ),
+                &self.cluster_label_names(&[]),
+                registry
+            )
+            .expect("Failed to generate aws_ecs_cluster_active_services metric family"),
+            running_tasks: register_int_gauge_vec_with_registry!(
+                opts!(
+                    self.metric_name("cluster_running_tasks"),
+                    "Tasks in the RUNNING state in the cluster"
+                ),
+                &self.cluster_label_names(&[]),
+                registry
+            )
+            .expect("Failed to generate aws_ecs_cluster_running_tasks metric family"),
+            pending_tasks: register_int_gauge_vec_with_registry!(
+                opts!(
+                    self.metric_name("cluster_pending_tasks"),
+                    "Tasks in the PENDING state in the cluster"
+                ),
+                &self.cluster_label_names(&[]),
+                registry
+            )
+            .expect("Failed to generate aws_ecs_cluster_pending_tasks metric family"),
+            statistic: register_int_gauge_vec_with_registry!(
+                opts!(
+                    self.metric_name("cluster_statistic"),
+                    "Additional statistics reported by the cluster"
+                ),
+                &self.cluster_label_names(&["statistic"]),
+                registry
+            )
+            .expect("Failed to generate aws_ecs_cluster_statistic metric family"),
+        };
+
+        let families = ClusterMetricFamilies {
+            scrape_metric,
+            task_metric_family,
+            resource_metric_family_registered,
+            resource_metric_family_remaining,
+            ports_metric_family,
+            instance_status_metric_family,
+            instance_status_info_metric_family,
+            instance_info_metric_family,
+            agent_connected_metric_family,
+            agent_info_metric_family,
+            attribute_metric_family,
+            service_metric_family_current,
+            service_metric_family_desired,
+            service_missing_metric_family,
+            service_info_metric_family,
+            cluster_info_metric_family,
+            service_created_metric_family,
+            deployment_updated_metric_family,
+            last_deployment_metric_family,
+            execute_command_metric_family,
+            deployment_blocked_metric_family,
+            deployment_metric_family,
+            deployments_total_metric_family,
+            load_balancer_metric_family,
+            image_metric_family,
+            task_definition_cpu_metric_family,
+            task_definition_memory_metric_family,
+            managed_agent_metric_family,
+            task_info_metric_family,
+            tasks_total_metric_family,
+            stopped_tasks_metric_family,
+            service_pagination_metrics,
+            target_health_metrics,
+            instance_pagination_metrics,
+            capacity_provider_metric_family,
+            default_capacity_provider_metric_family,
+            capacity_shortfall_metric_family,
+            quota_usage_metric_family,
+            scrape_duration_metric_family,
+            cluster_stats_metrics,
+        };
+
+        // Building the futures before streaming them spares the stream a closure over borrowed
+        // names, which the compiler can't prove `Send` for any lifetime
+        let scrapes: Vec<_> = cluster_names
+            .iter()
+            .map(|cluster_name| {
+                let cluster = Cluster {
+                    name: cluster_name,
+                    arn: self.emit_cluster_arn.then(|| {
+                        cluster_arns
+                            .get(cluster_name)
+                            .map(String::as_str)
+                            .unwrap_or_default()
+                    }),
+                    region: self.default_region.as_deref().map(|default_region| {
+                        self.cluster_regions
+                            .get(cluster_name.as_str())
+                            .map_or(default_region, String::as_str)
+                    }),
+                };
+                self.scrape_cluster(cluster, &families, &quotas)
+            })
+            .collect();
+        stream::iter(scrapes)
+            .buffer_unordered(self.cluster_parallelism)
+            .collect::<Vec<()>>()
+            .await;
         Ok(registry)
     }
 }
 
+fn set_capacity_provider_metrics(
+    cluster: Cluster<'_>,
+    cluster_details: &aws_sdk_ecs::model::Cluster,
+    capacity_provider_metric_family: &IntGaugeVec,
+    default_capacity_provider_metric_family: &IntGaugeVec,
+) {
+    let capacity_provider_count = cluster_details.capacity_providers.iter().flatten().count();
+    capacity_provider_metric_family
+        .with_label_values(&cluster.labels(&[]))
+        .set(capacity_provider_count as i64);
+
+    for item in cluster_details
+        .default_capacity_provider_strategy
+        .iter()
+        .flatten()
+    {
+        if let Some(capacity_provider) = &item.capacity_provider {
+            default_capacity_provider_metric_family
+                .with_label_values(&cluster.labels(&[
+                    capacity_provider,
+                    &item.weight.to_string(),
+                    &item.base.to_string(),
+                ]))
+                .set(1);
+        }
+    }
+}
+
+fn throttled_metric_family(metrics_prefix: &str) -> IntCounterVec {
+    IntCounterVec::new(
+        opts!(
+            format!("{}_api_throttled_total", metrics_prefix),
+            "Number of ECS API calls which were throttled"
+        ),
+        &["operation"],
+    )
+    .expect("Failed to create aws_ecs_api_throttled_total metric family")
+}
+
+fn api_calls_metric_family(metrics_prefix: &str, cluster_label: &str) -> IntCounterVec {
+    IntCounterVec::new(
+        opts!(
+            format!("{}_exporter_api_calls_total", metrics_prefix),
+            "Number of ECS API calls made for each cluster"
+        ),
+        &["operation", cluster_label],
+    )
+    .expect("Failed to create aws_ecs_exporter_api_calls_total metric family")
+}
+
+fn account_api_calls_metric_family(metrics_prefix: &str) -> IntCounterVec {
+    IntCounterVec::new(
+        opts!(
+            format!("{}_exporter_account_api_calls_total", metrics_prefix),
+            "Number of ECS API calls made which aren't specific to a cluster"
+        ),
+        &["operation"],
+    )
+    .expect("Failed to create aws_ecs_exporter_account_api_calls_total metric family")
+}
+
+/// A scrape which took longer than allowed
+#[derive(Debug)]
+struct TimedOut(Duration);
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Timed out after {:?}", self.0)
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Fails if the future doesn't complete within the timeout
+async fn with_timeout<T>(timeout: Duration, future: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::time::timeout(timeout, future)
+        .await
+        .map_err(|_| TimedOut(timeout))?
+}
+
+/// Classifies the error which failed a scrape, for alerts to tell the causes apart
+fn scrape_error_kind(err: &color_eyre::Report) -> &'static str {
+    if err.downcast_ref::<TimedOut>().is_some() {
+        return "timeout";
+    }
+    sdk_error_kind::<DescribeClustersError>(err)
+        .or_else(|| sdk_error_kind::<ListServicesError>(err))
+        .or_else(|| sdk_error_kind::<DescribeServicesError>(err))
+        .or_else(|| sdk_error_kind::<DescribeTaskDefinitionError>(err))
+        .or_else(|| sdk_error_kind::<ListContainerInstancesError>(err))
+        .or_else(|| sdk_error_kind::<DescribeContainerInstancesError>(err))
+        .or_else(|| sdk_error_kind::<ListTasksError>(err))
+        .or_else(|| sdk_error_kind::<DescribeTasksError>(err))
+        .unwrap_or("other")
+}
+
+/// Returns the kind of the error if it's an SDK error of the given operation
+fn sdk_error_kind<E>(err: &color_eyre::Report) -> Option<&'static str>
+where
+    E: ProvideErrorKind + std::error::Error + Send + Sync + 'static,
+{
+    let err = err.downcast_ref::<SdkError<E>>()?;
+    if is_throttling(err) {
+        return Some("throttling");
+    }
+    let kind = match err {
+        SdkError::ServiceError { err, .. } => match err.code() {
+            Some("AccessDeniedException") => "access_denied",
+            Some(code) if code.ends_with("NotFoundException") => "not_found",
+            _ => "other",
+        },
+        SdkError::TimeoutError(_) => "timeout",
+        _ => "other",
+    };
+    Some(kind)
+}
+
+/// Returns how many describe calls are needed for the given number of resources
+fn chunk_count(resource_count: usize, chunk_size: usize) -> usize {
+    resource_count.div_ceil(chunk_size)
+}
+
+fn set_quota_usage(
+    quota_usage_metric_family: &GaugeVec,
+    quotas: &HashMap<String, f64>,
+    cluster: Cluster<'_>,
+    quota_name: &str,
+    count: usize,
+) {
+    if let Some(quota) = quotas.get(quota_name).filter(|quota| **quota > 0.0) {
+        quota_usage_metric_family
+            .with_label_values(&cluster.labels(&[quota_name]))
+            .set(count as f64 / quota);
+    }
+}
+
+/// Returns when the most recent completed deployment of the service was last updated, in seconds
+fn last_completed_deployment(service: &Service) -> Option<f64> {
+    service
+        .deployments
+        .iter()
+        .flatten()
+        .filter(|deployment| deployment.rollout_state == Some(DeploymentRolloutState::Completed))
+        .filter_map(|deployment| deployment.updated_at.as_ref())
+        .map(|updated_at| updated_at.as_secs_f64())
+        .reduce(f64::max)
+}
+
+fn is_throttling<E: ProvideErrorKind>(err: &SdkError<E>) -> bool {
+    match err {
+        SdkError::ServiceError { err, .. } => {
+            err.retryable_error_kind() == Some(ErrorKind::ThrottlingError)
+                || err
+                    .code()
+                    .is_some_and(|code| THROTTLING_ERROR_CODES.contains(&code))
+        }
+        _ => false,
+    }
+}
+
+/// Whether the call failed for reasons which may go away by themselves
+fn is_transient<E: ProvideErrorKind>(err: &SdkError<E>) -> bool {
+    match err {
+        SdkError::ServiceError { err, .. } => {
+            err.retryable_error_kind() == Some(ErrorKind::TransientError)
+        }
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) => true,
+        _ => false,
+    }
+}
+
+/// Delay before the given retry, with up to half of it randomly taken off so that concurrent
+/// calls throttled together don't retry together
+fn retry_backoff(attempt: usize) -> Duration {
+    let delay = RETRY_BASE_DELAY
+        .saturating_mul(1u32 << attempt.min(16))
+        .min(RETRY_MAX_DELAY);
+    // No need for a proper random generator, the sub-second clock is unpredictable enough
+    let jitter = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.subsec_nanos()) as f64
+        / 1e9;
+    delay.mul_f64(1. - jitter / 2.)
+}
+
+/// Whether the service is to be exported, given its ARN
+///
+/// The ARN ends with the service name, in both the old and the new format. Exclusion wins.
+fn is_service_selected(arn: &str, include: Option<&Regex>, exclude: Option<&Regex>) -> bool {
+    let name = arn.rsplit('/').next().unwrap_or(arn);
+    include.is_none_or(|include| include.is_match(name))
+        && !exclude.is_some_and(|exclude| exclude.is_match(name))
+}
+
+/// The service's launch type, or the one implied by its capacity provider strategy
+///
+/// Services using a strategy have no launch type. The `FARGATE` and `FARGATE_SPOT` providers are
+/// the only ones not backed by EC2 instances.
+fn service_launch_type(service: &Service) -> &str {
+    if let Some(launch_type) = &service.launch_type {
+        return launch_type.as_str();
+    }
+    let providers: Vec<&str> = service
+        .capacity_provider_strategy
+        .iter()
+        .flatten()
+        .filter_map(|item| item.capacity_provider.as_deref())
+        .collect();
+    if providers.is_empty() {
+        "unknown"
+    } else if providers
+        .iter()
+        .any(|provider| provider.starts_with("FARGATE"))
+    {
+        "FARGATE"
+    } else {
+        "EC2"
+    }
+}
+
+/// Whether the newest deployment of the service failed while the circuit breaker is enabled
+fn is_deployment_blocked(service: &Service) -> bool {
+    let circuit_breaker_enabled = service
+        .deployment_configuration
+        .as_ref()
+        .and_then(|configuration| configuration.deployment_circuit_breaker.as_ref())
+        .is_some_and(|circuit_breaker| circuit_breaker.enable);
+    let newest_deployment = service.deployments.iter().flatten().max_by(|a, b| {
+        let created_at = |deployment: &Deployment| {
+            deployment
+                .created_at
+                .as_ref()
+                .map(|created_at| created_at.as_secs_f64())
+        };
+        created_at(a)
+            .partial_cmp(&created_at(b))
+            .unwrap_or(Ordering::Equal)
+    });
+    circuit_breaker_enabled
+        && newest_deployment.is_some_and(|deployment| {
+            deployment.rollout_state == Some(DeploymentRolloutState::Failed)
+        })
+}
+
+/// Returns the values of the instance labels, or `None` if the instance can't be identified
+///
+/// External (ECS Anywhere) instances have no EC2 instance ID, so they're identified by their
+/// container instance ARN instead.
+fn instance_label_values(
+    instance: &ContainerInstance,
+    instance_ip_label: bool,
+) -> Option<Vec<&str>> {
+    let ec2_instance_id = instance
+        .ec2_instance_id
+        .as_deref()
+        .filter(|id| !id.is_empty() && !is_external(instance));
+    let mut result = match ec2_instance_id {
+        Some(ec2_instance_id) => vec![ec2_instance_id, ec2_instance_id],
+        None => vec!["", instance.container_instance_arn.as_deref()?],
+    };
+    if instance_ip_label {
+        result.push(private_ip(instance).unwrap_or_default());
+    }
+    Some(result)
+}
+
+fn is_external(instance: &ContainerInstance) -> bool {
+    instance
+        .attributes
+        .iter()
+        .flatten()
+        .any(|attribute| attribute.name.as_deref() == Some("ecs.capability.external"))
+}
+
+/// Returns the values followed by the given one
+fn with_value<'a>(values: &[&'a str], value: &'a str) -> Vec<&'a str> {
+    let mut result = values.to_vec();
+    result.push(value);
+    result
+}
+
+/// Returns the private IP of the instance, if it's known from its attributes or attachments
+fn private_ip(instance: &ContainerInstance) -> Option<&str> {
+    let from_attributes = attribute_value(instance, "ecs.private-ip");
+    let from_attachments = || {
+        instance
+            .attachments
+            .iter()
+            .flatten()
+            .flat_map(|attachment| attachment.details.iter().flatten())
+            .find(|detail| detail.name.as_deref() == Some("privateIPv4Address"))
+            .and_then(|detail| detail.value.as_deref())
+    };
+    from_attributes.or_else(from_attachments)
+}
+
+/// Returns the value of the instance's attribute, if it has one
+fn attribute_value<'a>(instance: &'a ContainerInstance, name: &str) -> Option<&'a str> {
+    instance
+        .attributes
+        .iter()
+        .flatten()
+        .find(|attribute| attribute.name.as_deref() == Some(name))
+        .and_then(|attribute| attribute.value.as_deref())
+}
+
+/// Whether the instance has the attribute, given as either `name` or `name=value`
+fn has_attribute(instance: &ContainerInstance, counted_attribute: &str) -> bool {
+    let (name, value) = match counted_attribute.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (counted_attribute, None),
+    };
+    instance.attributes.iter().flatten().any(|attribute| {
+        attribute.name.as_deref() == Some(name)
+            && (value.is_none() || attribute.value.as_deref() == value)
+    })
+}
+
+/// Returns the CPU units and memory reserved by a task of the given definition
+///
+/// The task level values are used when set, otherwise the containers' are summed up.
+fn task_resources(task_definition: &TaskDefinition) -> [(&'static str, i64); 2] {
+    let containers = task_definition.container_definitions.iter().flatten();
+    let cpu = task_definition
+        .cpu
+        .as_deref()
+        .and_then(|cpu| cpu.parse().ok())
+        .unwrap_or_else(|| {
+            containers
+                .clone()
+                .map(|container| container.cpu as i64)
+                .sum()
+        });
+    let ram = task_definition
+        .memory
+        .as_deref()
+        .and_then(|memory| memory.parse().ok())
+        .unwrap_or_else(|| {
+            containers
+                .map(|container| container.memory.or(container.memory_reservation))
+                .map(|memory| memory.unwrap_or_default() as i64)
+                .sum()
+        });
+    [("cpu", cpu), ("ram", ram)]
+}
+
+/// Returns the label and value of the resources which are exported
 fn filter_resources(resource: &Resource) -> Option<(&'static str, i64)> {
     match resource.name.as_deref() {
         Some("CPU") => Some(("cpu", resource.integer_value as i64)),
         Some("MEMORY") => Some(("ram", resource.integer_value as i64)),
+        Some("GPU") => Some(("gpu", resource.integer_value as i64)),
         _ => None,
     }
 }
 
+/// Returns the protocol and number of the reserved ports, which ECS lists as a resource
+fn reserved_ports(resource: &Resource) -> Option<(&'static str, i64)> {
+    let protocol = match resource.name.as_deref() {
+        Some("PORTS") => "tcp",
+        Some("PORTS_UDP") => "udp",
+        _ => return None,
+    };
+    let port_count = resource.string_set_value.as_ref().map_or(0, Vec::len);
+    Some((protocol, port_count as i64))
+}
+
 fn log_failures(failures: Option<Vec<Failure>>) {
     if let Some(failures) = failures {
         for failure in failures {
@@ -342,3 +3226,424 @@ fn log_failures(failures: Option<Vec<Failure>>) {
         }
     }
 }
+
+/// Sorts the reason a task stopped into a few buckets, to bound the cardinality
+fn stopped_reason_bucket(task: &Task) -> &'static str {
+    let reasons: Vec<&str> = task
+        .stopped_reason
+        .as_deref()
+        .into_iter()
+        .chain(
+            task.containers
+                .iter()
+                .flatten()
+                .filter_map(|container| container.reason.as_deref()),
+        )
+        .collect();
+    let any_reason = |pattern: &str| reasons.iter().any(|reason| reason.contains(pattern));
+    if any_reason("OutOfMemory") {
+        "OutOfMemory"
+    } else if any_reason("RESOURCE:") || any_reason("ResourceInitializationError") {
+        "ResourceConstraint"
+    } else if any_reason("CannotPullContainer") {
+        "CannotPullContainer"
+    } else {
+        // The SDK only models some of the codes, the newer ones come as unknown variants
+        match task.stop_code.as_ref().map(TaskStopCode::as_str) {
+            Some("EssentialContainerExited") => "EssentialContainerExited",
+            Some("ServiceSchedulerInitiated") => "ServiceSchedulerInitiated",
+            Some("SpotInterruption") => "SpotInterruption",
+            Some("TerminationNotice") => "TerminationNotice",
+            Some("UserInitiated") => "UserInitiated",
+            _ => "Other",
+        }
+    }
+}
+
+/// Turns the tag key into a label name, replacing the characters labels can't have
+fn tag_label_name(tag_key: &str) -> String {
+    let sanitized: String = tag_key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("tag_{}", sanitized)
+}
+
+fn non_empty<T: Clone>(values: &[T]) -> Option<Vec<T>> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporter::tests::MockScraper;
+    use aws_sdk_ecs::model::{Attribute, CapacityProviderStrategyItem, Container};
+    use aws_smithy_types::retry::RetryConfig;
+    use aws_types::region::Region;
+
+    #[tokio::test]
+    async fn hung_scrape_times_out() {
+        let scraper = MockScraper::slow(Duration::from_secs(5));
+        let result = with_timeout(Duration::from_millis(10), scraper.scrape()).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn timely_scrape_succeeds() {
+        let result = with_timeout(Duration::from_secs(1), async { Ok(42) }).await;
+        assert_eq!(result.unwrap(), 42);
+    }
+
+    #[test]
+    fn resources_are_mapped_to_labels() {
+        let resource = |name: &str| Resource::builder().name(name).integer_value(4).build();
+        assert_eq!(filter_resources(&resource("CPU")), Some(("cpu", 4)));
+        assert_eq!(filter_resources(&resource("MEMORY")), Some(("ram", 4)));
+        assert_eq!(filter_resources(&resource("GPU")), Some(("gpu", 4)));
+        assert_eq!(filter_resources(&resource("SOMETHING")), None);
+
+        let ports = Resource::builder()
+            .name("PORTS")
+            .string_set_value("22")
+            .string_set_value("2376")
+            .build();
+        assert_eq!(filter_resources(&ports), None);
+        assert_eq!(reserved_ports(&ports), Some(("tcp", 2)));
+    }
+
+    #[test]
+    fn external_instance_is_identified_by_its_arn() {
+        let arn = "arn:aws:ecs:eu-west-1:123456789012:container-instance/abc";
+        let instance = ContainerInstance::builder()
+            .container_instance_arn(arn)
+            .build();
+        assert_eq!(instance_label_values(&instance, false), Some(vec!["", arn]));
+        let instance = ContainerInstance::builder()
+            .container_instance_arn(arn)
+            .ec2_instance_id("mi-0123456789abcdef0")
+            .attributes(Attribute::builder().name("ecs.capability.external").build())
+            .build();
+        assert_eq!(instance_label_values(&instance, false), Some(vec!["", arn]));
+    }
+
+    #[test]
+    fn instance_without_any_identifier_is_skipped() {
+        let instance = ContainerInstance::builder().build();
+        assert_eq!(instance_label_values(&instance, false), None);
+        assert_eq!(instance_label_values(&instance, true), None);
+    }
+
+    /// Without credentials, every call fails before reaching the network. Neither the SDK nor
+    /// the exporter retry them, so failing scrapes don't wait for any backoff.
+    fn offline_client<C: AsRef<str>>(cluster_names: &[C]) -> EcsClient {
+        let config = aws_sdk_ecs::Config::builder()
+            .region(Region::new("eu-west-1"))
+            .retry_config(RetryConfig::disabled())
+            .build();
+        EcsClient::new(aws_sdk_ecs::Client::from_conf(config), cluster_names).with_max_retries(0)
+    }
+
+    #[test]
+    fn duplicate_clusters_are_ignored() {
+        let client = offline_client(&["first", "second", "first"]);
+        assert_eq!(*client.clusters.snapshot(), vec!["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn no_clusters_give_an_empty_scrape() {
+        let client = offline_client(&[] as &[&str]);
+        let families = client.scrape().await.unwrap().gather();
+        assert!(families
+            .iter()
+            .filter(|family| family.get_name().starts_with("aws_ecs_cluster_"))
+            .all(|family| family.get_metric().is_empty()));
+    }
+
+    #[tokio::test]
+    async fn metrics_prefix_applies_to_all_families() {
+        let client = offline_client(&["some-cluster"]).with_metrics_prefix("custom");
+        client.count_api_call("ListServices", "some-cluster");
+        let families = client.scrape().await.unwrap().gather();
+        assert!(!families.is_empty());
+        for family in &families {
+            assert!(
+                family.get_name().starts_with("custom_"),
+                "{} isn't prefixed",
+                family.get_name()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn api_calls_are_labelled_with_the_cluster_label() {
+        let client = offline_client(&["some-cluster"]).with_cluster_label("ecs_cluster");
+        client.count_api_call("ListServices", "some-cluster");
+        client.count_account_api_call("ListClusters");
+        let families = client.scrape().await.unwrap().gather();
+        let label_names = |name: &str| -> Vec<String> {
+            let family = families
+                .iter()
+                .find(|family| family.get_name() == name)
+                .unwrap();
+            family.get_metric()[0]
+                .get_label()
+                .iter()
+                .map(|pair| pair.get_name().to_string())
+                .collect()
+        };
+        assert_eq!(
+            label_names("aws_ecs_exporter_api_calls_total"),
+            vec!["ecs_cluster", "operation"]
+        );
+        assert_eq!(
+            label_names("aws_ecs_exporter_account_api_calls_total"),
+            vec!["operation"]
+        );
+    }
+
+    #[tokio::test]
+    async fn disabled_collectors_are_not_scraped() {
+        let client =
+            offline_client(&["some-cluster"]).with_disabled_collectors(vec![Collector::Services]);
+        let families = client.scrape().await.unwrap().gather();
+        let scrape_success = families
+            .iter()
+            .find(|family| family.get_name() == "aws_ecs_cluster_scrape_success")
+            .expect("scrape success should be exported");
+        let scraped_resources: Vec<&str> = scrape_success
+            .get_metric()
+            .iter()
+            .flat_map(|metric| metric.get_label())
+            .filter(|label| label.get_name() == "scraped_resource")
+            .map(|label| label.get_value())
+            .collect();
+        assert_eq!(
+            scraped_resources,
+            vec!["cluster_instances", "cluster_stats"]
+        );
+    }
+
+    #[tokio::test]
+    async fn clusters_are_not_hidden_when_nothing_was_counted() {
+        let client = offline_client(&["some-cluster"])
+            .with_disabled_collectors(vec![Collector::Instances, Collector::Services])
+            .with_hide_empty_clusters(true);
+        let families = client.scrape().await.unwrap().gather();
+        let scrape_success = families
+            .iter()
+            .find(|family| family.get_name() == "aws_ecs_cluster_scrape_success")
+            .expect("scrape success should be exported");
+        assert_eq!(scrape_success.get_metric().len(), 1);
+    }
+
+    #[test]
+    fn stopped_reasons_are_bucketed() {
+        let oom_killed = Task::builder()
+            .stop_code(TaskStopCode::EssentialContainerExited)
+            .stopped_reason("Essential container in task exited")
+            .containers(
+                Container::builder()
+                    .reason("OutOfMemoryError: Container killed due to memory usage")
+                    .build(),
+            )
+            .build();
+        assert_eq!(stopped_reason_bucket(&oom_killed), "OutOfMemory");
+
+        let exited = Task::builder()
+            .stop_code(TaskStopCode::EssentialContainerExited)
+            .stopped_reason("Essential container in task exited")
+            .build();
+        assert_eq!(stopped_reason_bucket(&exited), "EssentialContainerExited");
+
+        let pull_failed = Task::builder()
+            .stop_code(TaskStopCode::TaskFailedToStart)
+            .stopped_reason("CannotPullContainerError: pull image manifest has been retried")
+            .build();
+        assert_eq!(stopped_reason_bucket(&pull_failed), "CannotPullContainer");
+
+        let no_secrets = Task::builder()
+            .stop_code(TaskStopCode::TaskFailedToStart)
+            .stopped_reason("ResourceInitializationError: unable to pull secrets")
+            .build();
+        assert_eq!(stopped_reason_bucket(&no_secrets), "ResourceConstraint");
+
+        let unknown = Task::builder()
+            .stop_code(TaskStopCode::TaskFailedToStart)
+            .build();
+        assert_eq!(stopped_reason_bucket(&unknown), "Other");
+        assert_eq!(stopped_reason_bucket(&Task::builder().build()), "Other");
+    }
+
+    #[test]
+    fn tag_keys_are_sanitized_into_label_names() {
+        assert_eq!(tag_label_name("team"), "tag_team");
+        assert_eq!(
+            tag_label_name("aws:cloudformation:stack-name"),
+            "tag_aws_cloudformation_stack_name"
+        );
+        assert_eq!(tag_label_name("2fa"), "tag_2fa");
+    }
+
+    #[test]
+    fn missing_tags_are_empty_label_values() {
+        let client =
+            offline_client(&["some-cluster"]).with_tag_labels(&["team", "environment", "team"]);
+        let tags = vec![Tag::builder().key("environment").value("prod").build()];
+        assert_eq!(client.tag_label_values(Some(&tags)), vec!["", "prod"]);
+        assert_eq!(client.tag_label_values(None), vec!["", ""]);
+    }
+
+    #[test]
+    fn scrape_errors_are_classified() {
+        assert_eq!(
+            scrape_error_kind(&color_eyre::Report::new(TimedOut(Duration::from_secs(1)))),
+            "timeout"
+        );
+        let sdk_timeout = SdkError::<ListTasksError>::TimeoutError("too slow".into());
+        assert_eq!(
+            scrape_error_kind(&color_eyre::Report::new(sdk_timeout)),
+            "timeout"
+        );
+        assert_eq!(scrape_error_kind(&eyre!("something else")), "other");
+    }
+
+    #[tokio::test]
+    async fn failed_scrape_is_exported_as_zero() {
+        let client = offline_client(&["some-cluster"]);
+        let families = client.scrape().await.unwrap().gather();
+        let scrape_success = families
+            .iter()
+            .find(|family| family.get_name() == "aws_ecs_cluster_scrape_success")
+            .expect("scrape success should be exported");
+        for scraped_resource in ["cluster_stats", "cluster_instances", "services"] {
+            let metric = scrape_success
+                .get_metric()
+                .iter()
+                .find(|metric| {
+                    metric.get_label().iter().any(|label| {
+                        label.get_name() == "scraped_resource"
+                            && label.get_value() == scraped_resource
+                    })
+                })
+                .unwrap_or_else(|| {
+                    panic!("{} should have a scrape success series", scraped_resource)
+                });
+            assert_eq!(metric.get_gauge().get_value(), 0.);
+        }
+    }
+
+    #[test]
+    fn services_are_filtered_by_name() {
+        let include = Regex::new("^api-").unwrap();
+        let exclude = Regex::new("-canary$").unwrap();
+        let arn = |name: &str| {
+            format!(
+                "arn:aws:ecs:eu-west-1:123456789012:service/cluster/{}",
+                name
+            )
+        };
+
+        assert!(is_service_selected(
+            &arn("api-users"),
+            Some(&include),
+            Some(&exclude)
+        ));
+        assert!(!is_service_selected(
+            &arn("worker"),
+            Some(&include),
+            Some(&exclude)
+        ));
+        assert!(!is_service_selected(
+            &arn("api-users-canary"),
+            Some(&include),
+            Some(&exclude)
+        ));
+        assert!(!is_service_selected(
+            &arn("worker-canary"),
+            None,
+            Some(&exclude)
+        ));
+        assert!(is_service_selected(&arn("worker"), None, Some(&exclude)));
+        // Old format ARNs don't have the cluster name
+        assert!(is_service_selected(
+            "arn:aws:ecs:eu-west-1:123456789012:service/api-users",
+            Some(&include),
+            None
+        ));
+    }
+
+    #[test]
+    fn service_launch_type_falls_back_to_capacity_providers() {
+        let strategy = |provider: &str| {
+            CapacityProviderStrategyItem::builder()
+                .capacity_provider(provider)
+                .build()
+        };
+        let service = Service::builder().launch_type(LaunchType::Ec2).build();
+        assert_eq!(service_launch_type(&service), "EC2");
+        let service = Service::builder()
+            .capacity_provider_strategy(strategy("FARGATE_SPOT"))
+            .build();
+        assert_eq!(service_launch_type(&service), "FARGATE");
+        let service = Service::builder()
+            .capacity_provider_strategy(strategy("some-asg-provider"))
+            .build();
+        assert_eq!(service_launch_type(&service), "EC2");
+        assert_eq!(service_launch_type(&Service::builder().build()), "unknown");
+    }
+
+    #[tokio::test]
+    async fn fargate_capacity_provider_services_demand_no_capacity() {
+        let client = offline_client(&["first"]);
+        let service = Service::builder()
+            .capacity_provider_strategy(
+                CapacityProviderStrategyItem::builder()
+                    .capacity_provider("FARGATE_SPOT")
+                    .build(),
+            )
+            .desired_count(2)
+            .task_definition("arn:aws:ecs:eu-west-1:123456789012:task-definition/app:1")
+            .build();
+        let cluster = Cluster {
+            name: "first",
+            arn: None,
+            region: None,
+        };
+        let mut capacity_estimate = CapacityEstimate::default();
+
+        client
+            .add_capacity_demand(cluster, &service, &mut capacity_estimate)
+            .await;
+
+        assert!(capacity_estimate.demanded.is_empty());
+    }
+
+    #[test]
+    fn instance_label_values_include_private_ip() {
+        let instance = ContainerInstance::builder()
+            .ec2_instance_id("i-0123456789abcdef0")
+            .attributes(
+                Attribute::builder()
+                    .name("ecs.private-ip")
+                    .value("10.0.0.1")
+                    .build(),
+            )
+            .build();
+        assert_eq!(
+            instance_label_values(&instance, false),
+            Some(vec!["i-0123456789abcdef0", "i-0123456789abcdef0"])
+        );
+        assert_eq!(
+            instance_label_values(&instance, true),
+            Some(vec![
+                "i-0123456789abcdef0",
+                "i-0123456789abcdef0",
+                "10.0.0.1"
+            ])
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use color_eyre::Result;
+use std::collections::HashMap;
+
+/// Service code under which ECS quotas are registered in Service Quotas
+const ECS_SERVICE_CODE: &str = "ecs";
+
+/// Names of the quotas whose usage can be derived from what a scrape observes
+pub const SERVICES_PER_CLUSTER: &str = "Services per cluster";
+pub const INSTANCES_PER_CLUSTER: &str = "Container instances per cluster";
+pub const TASKS_PER_SERVICE: &str = "Tasks per service";
+
+/// Returns the applied ECS quotas for the account, indexed by quota name
+pub async fn get_ecs_quotas(
+    client: &aws_sdk_servicequotas::Client,
+) -> Result<HashMap<String, f64>> {
+    let mut next_token = None;
+    let mut result = HashMap::new();
+    loop {
+        let response = client
+            .list_service_quotas()
+            .service_code(ECS_SERVICE_CODE)
+            .set_next_token(next_token)
+            .send()
+            .await?;
+        if let Some(quotas) = response.quotas {
+            result.extend(
+                quotas
+                    .into_iter()
+                    .filter_map(|quota| Some((quota.quota_name?, quota.value?))),
+            );
+        }
+        next_token = response.next_token;
+        if next_token.is_none() {
+            break;
+        }
+    }
+    Ok(result)
+}
@@ -0,0 +1,26 @@
+use aws_sdk_elasticloadbalancingv2::model::TargetHealthStateEnum;
+use aws_sdk_elasticloadbalancingv2::output::DescribeTargetHealthOutput;
+
+/// Number of targets in a target group, by health as seen by the load balancer
+pub struct TargetHealthCounts {
+    pub healthy: usize,
+    pub unhealthy: usize,
+}
+
+/// Counts how many targets of the described target group are healthy and unhealthy
+///
+/// Targets in other states, such as initial or draining, are counted in neither.
+pub fn count_target_health(response: DescribeTargetHealthOutput) -> TargetHealthCounts {
+    let mut result = TargetHealthCounts {
+        healthy: 0,
+        unhealthy: 0,
+    };
+    for description in response.target_health_descriptions.into_iter().flatten() {
+        match description.target_health.and_then(|health| health.state) {
+            Some(TargetHealthStateEnum::Healthy) => result.healthy += 1,
+            Some(TargetHealthStateEnum::Unhealthy) => result.unhealthy += 1,
+            _ => {}
+        }
+    }
+    result
+}
@@ -0,0 +1,148 @@
+use crate::exporter::Scraper;
+use async_trait::async_trait;
+use aws_sdk_cloudwatch::model::{Dimension, MetricDatum};
+use color_eyre::Result;
+use prometheus::proto::{MetricFamily, MetricType};
+use prometheus::Registry;
+use std::sync::Arc;
+use tracing::warn;
+
+/// Maximum number of metrics accepted by a single PutMetricData call
+const MAX_METRICS_PER_CALL: usize = 1000;
+/// CloudWatch rejects values whose magnitude is outside of this range, other than zero
+const MIN_MAGNITUDE: f64 = 8.515920e-109;
+const MAX_MAGNITUDE: f64 = 1.174271e+108;
+
+/// Gauges pushed to CloudWatch, by name without the prefix, along with the label whose values
+/// each get their own CloudWatch metric, if any
+///
+/// The others aren't pushed, as every series is a custom metric which CloudWatch charges for.
+const PUSHED_FAMILIES: [(&str, Option<&str>); 7] = [
+    ("cluster_registered_container_instances", None),
+    ("cluster_active_services", None),
+    ("cluster_running_tasks", None),
+    ("cluster_pending_tasks", None),
+    ("service_desired", None),
+    ("service_current_total", Some("state")),
+    ("service_missing", None),
+];
+
+/// Label of the service, the only dimension other than the cluster
+const SERVICE_LABEL: &str = "service_name";
+
+/// Pushes a few cluster and service gauges of every scrape to CloudWatch as custom metrics
+///
+/// Pushing happens in the background, and failing to is only logged, so that it doesn't affect
+/// the Prometheus scrape.
+pub struct CloudWatchScraper {
+    scraper: Arc<dyn Scraper>,
+    client: aws_sdk_cloudwatch::Client,
+    namespace: String,
+    metrics_prefix: String,
+    cluster_label: String,
+}
+
+impl CloudWatchScraper {
+    pub fn new(
+        scraper: Arc<dyn Scraper>,
+        client: aws_sdk_cloudwatch::Client,
+        namespace: &str,
+        metrics_prefix: &str,
+        cluster_label: &str,
+    ) -> Self {
+        Self {
+            scraper,
+            client,
+            namespace: namespace.to_string(),
+            metrics_prefix: metrics_prefix.to_string(),
+            cluster_label: cluster_label.to_string(),
+        }
+    }
+
+    /// Converts the pushed gauges to CloudWatch metrics, with only the cluster and service as
+    /// dimensions
+    fn to_metric_data(&self, metric_families: &[MetricFamily]) -> Vec<MetricDatum> {
+        let mut result = vec![];
+        for family in metric_families
+            .iter()
+            .filter(|family| family.get_field_type() == MetricType::GAUGE)
+        {
+            let split_label = match family
+                .get_name()
+                .strip_prefix(&self.metrics_prefix)
+                .and_then(|name| name.strip_prefix('_'))
+                .and_then(|name| PUSHED_FAMILIES.iter().find(|(pushed, _)| *pushed == name))
+            {
+                Some((_, split_label)) => split_label,
+                None => continue,
+            };
+            for metric in family.get_metric() {
+                let value = metric.get_gauge().get_value();
+                if !is_valid_value(value) {
+                    continue;
+                }
+                let mut metric_name = family.get_name().to_string();
+                let mut dimensions = vec![];
+                for pair in metric.get_label() {
+                    if Some(pair.get_name()) == *split_label {
+                        metric_name = format!("{}_{}", metric_name, pair.get_value());
+                    } else if (pair.get_name() == self.cluster_label
+                        || pair.get_name() == SERVICE_LABEL)
+                        && !pair.get_value().is_empty()
+                    {
+                        dimensions.push(
+                            Dimension::builder()
+                                .name(pair.get_name())
+                                .value(pair.get_value())
+                                .build(),
+                        );
+                    }
+                }
+                result.push(
+                    MetricDatum::builder()
+                        .metric_name(metric_name)
+                        .set_dimensions(Some(dimensions))
+                        .value(value)
+                        .build(),
+                );
+            }
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl Scraper for CloudWatchScraper {
+    async fn scrape(&self) -> Result<Registry> {
+        let registry = self.scraper.scrape().await?;
+        let metric_data = self.to_metric_data(&registry.gather());
+        let client = self.client.clone();
+        let namespace = self.namespace.clone();
+        tokio::spawn(async move {
+            if let Err(err) = push(&client, &namespace, metric_data).await {
+                warn!("Failed to push metrics to CloudWatch: {}", err);
+            }
+        });
+        Ok(registry)
+    }
+}
+
+async fn push(
+    client: &aws_sdk_cloudwatch::Client,
+    namespace: &str,
+    metric_data: Vec<MetricDatum>,
+) -> Result<()> {
+    for chunk in metric_data.chunks(MAX_METRICS_PER_CALL) {
+        client
+            .put_metric_data()
+            .namespace(namespace)
+            .set_metric_data(Some(chunk.to_vec()))
+            .send()
+            .await?;
+    }
+    Ok(())
+}
+
+fn is_valid_value(value: f64) -> bool {
+    value == 0.0 || (MIN_MAGNITUDE..=MAX_MAGNITUDE).contains(&value.abs())
+}
@@ -1,7 +1,51 @@
+use crate::aws::{
+    Collector, DEFAULT_CLUSTER_LABEL, DEFAULT_CLUSTER_PARALLELISM, DEFAULT_MAX_CONCURRENCY,
+    DEFAULT_MAX_RETRIES, DEFAULT_MAX_STOPPED_TASKS, DEFAULT_METRICS_PREFIX, DEFAULT_SCRAPE_TIMEOUT,
+    RESERVED_LABEL_NAMES,
+};
+use crate::remote_write::RemoteWriteConfig;
+#[cfg(feature = "bench")]
+use crate::sample::{self, SampleConfig};
+use aws_sdk_ecs::model::{ContainerInstanceField, ServiceField};
 use aws_types::region::Region;
-use clap::{app_from_crate, crate_version, AppSettings, Arg};
+use aws_types::Credentials;
+use clap::{
+    app_from_crate, crate_version, App, AppSettings, Arg, ArgGroup, ArgSettings, ErrorKind,
+};
 use regex::Regex;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
 use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use warp::http::Uri;
+
+/// Matches IAM role ARNs in any partition, including those with a path
+const ROLE_ARN_PATTERN: &str =
+    r"^arn:aws(-cn|-us-gov)?:iam::\d{12}:role/([\w+=,.@-]+/)*[\w+=,.@-]+$";
+
+/// Matches Prometheus label names, excluding those reserved for internal use
+const LABEL_NAME_PATTERN: &str = r"^([a-zA-Z]|_[a-zA-Z0-9])[a-zA-Z0-9_]*$";
+
+/// Matches Prometheus metric names
+const METRIC_NAME_PATTERN: &str = r"^[a-zA-Z_:][a-zA-Z0-9_:]*$";
+
+const CONFIG_FILE_ENV: &str = "ECS_EXPORTER_CONFIG_FILE";
+
+/// Paths of the endpoints served besides the metrics
+const RESERVED_PATHS: [&str; 3] = ["status", "ready", "refresh"];
+
+/// Shortest lease, in seconds, so that renewing it every third of it doesn't hammer DynamoDB
+const MIN_HA_LEASE: u64 = 3;
+
+/// Long enough for a distant region, short enough to leave time for retries within a scrape
+const DEFAULT_AWS_CONNECT_TIMEOUT: &str = "3";
+const DEFAULT_AWS_OPERATION_TIMEOUT: &str = "10";
 
 #[derive(Debug)]
 pub struct TlsConfig {
@@ -9,32 +53,244 @@ pub struct TlsConfig {
     pub cert: String,
 }
 
+#[derive(Debug)]
+pub struct WebIdentityConfig {
+    pub token_file: String,
+    pub role: String,
+}
+
+#[derive(Debug)]
+pub struct BasicAuthConfig {
+    pub username: String,
+    pub password: String,
+}
+
 #[derive(Debug)]
 pub struct Config {
-    pub cluster_names: Vec<String>,
+    pub clusters: Vec<ClusterConfig>,
+    pub all_clusters: bool,
+    pub cluster_tags: Vec<(String, String)>,
+    pub tag_labels: Vec<String>,
     pub aws_role: Option<String>,
-    pub listen_address: SocketAddr,
+    pub external_id: Option<String>,
+    pub session_name: Option<String>,
+    pub static_credentials: Option<Credentials>,
+    pub web_identity: Option<WebIdentityConfig>,
+    pub no_imds: bool,
+    pub sts_endpoint: Option<String>,
+    pub listen_addresses: Vec<ListenAddress>,
+    pub tls: Option<TlsConfig>,
+    pub basic_auth: Option<BasicAuthConfig>,
+    pub metrics_path: String,
     pub region: Option<Region>,
     pub app_version: String,
+    pub service_include: Vec<ServiceField>,
+    pub service_name_include: Option<Regex>,
+    pub service_name_exclude: Option<Regex>,
+    pub instance_include: Vec<ContainerInstanceField>,
+    pub with_quotas: bool,
+    pub with_images: bool,
+    pub with_task_definitions: bool,
+    pub disabled_collectors: Vec<Collector>,
+    pub remote_write: Option<RemoteWriteConfig>,
+    pub max_stale: Option<Duration>,
+    pub cache_ttl: Option<Duration>,
+    pub ha_lock: Option<HaLockConfig>,
+    pub max_concurrency: usize,
+    pub cluster_concurrency: HashMap<String, usize>,
+    pub shared_concurrency: Option<usize>,
+    pub cluster_parallelism: usize,
+    pub describe_chunk_size: Option<usize>,
+    pub max_retries: usize,
+    pub scrape_timeout: Duration,
+    pub aws_connect_timeout: Duration,
+    pub aws_operation_timeout: Duration,
+    pub ready_window: Duration,
+    pub counted_attributes: Vec<String>,
+    pub with_managed_agents: bool,
+    pub with_tasks: bool,
+    pub max_stopped_tasks: Option<usize>,
+    pub cloudwatch_namespace: Option<String>,
+    pub cluster_label: String,
+    pub hide_empty_clusters: bool,
+    pub emit_cluster_arn: bool,
+    pub sts_check: bool,
+    pub with_target_health: bool,
+    pub instance_ip_label: bool,
+    pub exporter_name: String,
+    /// Only set when given, so that the unprefixed metrics keep their names by default
+    pub metrics_prefix: Option<String>,
+    pub log_format: LogFormat,
+    pub log_level: String,
+    /// Problems found while parsing, to be logged once logging is set up
+    pub warnings: Vec<String>,
+    pub fail_fast: bool,
+    pub once: bool,
+    pub with_capacity_shortfall: bool,
+    pub omit_registered_resources: bool,
+    #[cfg(feature = "bench")]
+    pub sample: Option<SampleConfig>,
+}
+
+/// Where to serve the metrics, either a TCP address or a Unix socket given as `unix:PATH`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some("") => Err("the socket path must not be empty".to_string()),
+            #[cfg(unix)]
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            #[cfg(not(unix))]
+            Some(_) => Err("Unix sockets aren't supported on this platform".to_string()),
+            None => value
+                .parse()
+                .map(Self::Tcp)
+                .map_err(|err| format!("{}", err)),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(socket_address) => write!(f, "{}", socket_address),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// A cluster to scrape, optionally with the role to assume and the region to use for it instead
+/// of the global ones
+#[derive(Debug, PartialEq)]
+pub struct ClusterConfig {
+    pub name: String,
+    pub role: Option<String>,
+    pub region: Option<String>,
+}
+
+/// The contents of a configuration file, whose keys are the long names of the options
+#[derive(Debug, Deserialize)]
+struct FileConfig {
+    #[serde(default)]
+    clusters: Vec<FileCluster>,
+    #[serde(flatten)]
+    options: BTreeMap<String, FileValue>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileCluster {
+    name: String,
+    role: Option<String>,
+    region: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum FileValue {
+    Flag(bool),
+    Number(i64),
+    Text(String),
+    List(Vec<String>),
+}
+
+impl fmt::Display for FileValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Flag(flag) => write!(f, "{}", flag),
+            Self::Number(number) => write!(f, "{}", number),
+            Self::Text(text) => write!(f, "{}", text),
+            Self::List(values) => write!(f, "{}", values.join(",")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HaLockConfig {
+    pub table_name: String,
+    pub lock_name: String,
+    pub lease: Duration,
 }
 
 impl Config {
     pub fn from_args() -> Self {
-        let role_re: Regex = Regex::new(r"(?i:arn:aws:iam::\d{12}:role/.*)").unwrap();
-        let matches = app_from_crate!()
+        let role_re: Regex = Regex::new(ROLE_ARN_PATTERN).unwrap();
+        let metric_name_re: Regex = Regex::new(METRIC_NAME_PATTERN).unwrap();
+        let default_max_concurrency = DEFAULT_MAX_CONCURRENCY.to_string();
+        let default_cluster_parallelism = DEFAULT_CLUSTER_PARALLELISM.to_string();
+        let default_max_retries = DEFAULT_MAX_RETRIES.to_string();
+        let default_max_stopped_tasks = DEFAULT_MAX_STOPPED_TASKS.to_string();
+        let default_scrape_timeout = DEFAULT_SCRAPE_TIMEOUT.as_secs().to_string();
+        let app = app_from_crate!()
             .setting(AppSettings::DeriveDisplayOrder)
             .term_width(120)
             .args(&[
+                Arg::new("config_file")
+                    .long("config-file")
+                    .takes_value(true)
+                    .value_name("PATH")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env(CONFIG_FILE_ENV)
+                    .help("YAML or TOML (if ending in `.toml`) file whose keys are the long names of these options, e.g. `max-retries`, plus `clusters` as a list of `name`, `role` and `region`. Options given on the command line or through the environment take precedence"),
                 Arg::new("clusters")
                     .long("cluster")
                     .takes_value(true)
                     .value_name("CLUSTER")
-                    .required(true)
+                    .required_unless_present("all_clusters")
+                    .conflicts_with("all_clusters")
                     .multiple_occurrences(true)
                     .multiple_values(true)
                     .forbid_empty_values(true)
                     .env("ECS_EXPORTER_CLUSTERS")
-                    .help("Cluster name (one or more)"),
+                    .validator(|value| parse_cluster(value).map(|_| ()))
+                    .help("Cluster name (one or more), optionally as `name=NAME,role=ROLE_ARN,region=REGION` to assume a specific role or use another region, both being optional"),
+                Arg::new("all_clusters")
+                    .long("all-clusters")
+                    .takes_value(false)
+                    .required(false)
+                    .env("ECS_EXPORTER_ALL_CLUSTERS")
+                    .help("Scrape all the clusters of the account instead of those given with --cluster"),
+                Arg::new("cluster_tags")
+                    .long("cluster-tag")
+                    .takes_value(true)
+                    .value_name("KEY=VALUE")
+                    .required(false)
+                    .multiple_occurrences(true)
+                    .multiple_values(true)
+                    .forbid_empty_values(true)
+                    .requires("all_clusters")
+                    .env("ECS_EXPORTER_CLUSTER_TAGS")
+                    .validator(validate_label_pair)
+                    .help("Only scrape the discovered clusters having this tag, all of them if given several times"),
+                Arg::new("tag_labels")
+                    .long("tag-label")
+                    .takes_value(true)
+                    .value_name("TAG_KEY")
+                    .required(false)
+                    .multiple_occurrences(true)
+                    .multiple_values(true)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_TAG_LABELS")
+                    .help("Add the value of this tag to the service and cluster info metrics, as a `tag_TAG_KEY` label"),
                 Arg::new("region")
                     .long("region")
                     .takes_value(true)
@@ -44,7 +300,7 @@ impl Config {
                     .multiple_values(false)
                     .forbid_empty_values(true)
                     .env("AWS_REGION")
-                    .help("AWS Region to use, if any"),
+                    .help("AWS Region to use, if any, unless the cluster sets its own"),
                 Arg::new("role")
                     .long("role")
                     .takes_value(true)
@@ -55,42 +311,1356 @@ impl Config {
                     .forbid_empty_values(true)
                     .env("ECS_EXPORTER_ROLE")
                     .validator_regex(
-                        role_re,
-                        "must be of the form `arn:aws:iam::123456789012:role/something`",
+                        &role_re,
+                        "must be of the form `arn:<partition>:iam::123456789012:role/something`",
                     )
                     .help("AWS Role to assume, if any"),
+                Arg::new("external_id")
+                    .long("aws-external-id")
+                    .takes_value(true)
+                    .value_name("EXTERNAL_ID")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_EXTERNAL_ID")
+                    .help("External ID to pass when assuming the role"),
+                Arg::new("session_name")
+                    .long("aws-session-name")
+                    .takes_value(true)
+                    .value_name("SESSION_NAME")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_SESSION_NAME")
+                    .help("Session name to use when assuming the role"),
+                Arg::new("sts_endpoint")
+                    .long("sts-endpoint")
+                    .takes_value(true)
+                    .value_name("URL")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .validator(|value| value.parse::<Uri>().map(|_| ()))
+                    .env("ECS_EXPORTER_STS_ENDPOINT")
+                    .help("STS endpoint to assume roles through, e.g. a VPC endpoint"),
+                Arg::new("web_identity_token_file")
+                    .long("web-identity-token-file")
+                    .takes_value(true)
+                    .value_name("PATH")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .requires("web_identity_role")
+                    .conflicts_with("access_key_id")
+                    .env("AWS_WEB_IDENTITY_TOKEN_FILE")
+                    .help("Web identity token to get the base credentials with, e.g. for IRSA"),
+                Arg::new("web_identity_role")
+                    .long("web-identity-role")
+                    .takes_value(true)
+                    .value_name("AWS_ROLE")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("AWS_ROLE_ARN")
+                    .validator_regex(
+                        &role_re,
+                        "must be of the form `arn:<partition>:iam::123456789012:role/something`",
+                    )
+                    .help("Role the web identity token is exchanged for"),
+                Arg::new("access_key_id")
+                    .long("aws-access-key-id")
+                    .takes_value(true)
+                    .value_name("KEY_ID")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .requires("secret_access_key")
+                    .env("ECS_EXPORTER_AWS_ACCESS_KEY_ID")
+                    .help("Static access key to use instead of the default credentials chain"),
+                Arg::new("secret_access_key")
+                    .long("aws-secret-access-key")
+                    .takes_value(true)
+                    .value_name("SECRET")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .requires("access_key_id")
+                    .env("ECS_EXPORTER_AWS_SECRET_ACCESS_KEY")
+                    .hide_env_values(true)
+                    .help("Secret of the static access key"),
+                Arg::new("session_token")
+                    .long("aws-session-token")
+                    .takes_value(true)
+                    .value_name("TOKEN")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .requires("access_key_id")
+                    .env("ECS_EXPORTER_AWS_SESSION_TOKEN")
+                    .hide_env_values(true)
+                    .help("Session token of the static access key, if it's temporary"),
+                Arg::new("no_imds")
+                    .long("no-imds")
+                    .takes_value(false)
+                    .required(false)
+                    .env("ECS_EXPORTER_NO_IMDS")
+                    .help("Don't look up the credentials and region in the EC2 instance metadata, e.g. to fail faster outside of EC2"),
                 Arg::new("listen")
                     .short('l')
                     .long("listen")
                     .takes_value(true)
                     .value_name("LISTEN")
                     .required(false)
-                    .multiple_occurrences(false)
-                    .multiple_values(false)
+                    .multiple_occurrences(true)
+                    .multiple_values(true)
                     .forbid_empty_values(true)
                     .env("ECS_EXPORTER_LISTEN")
-                    .default_value("[::1]:6543")
+                    .default_value("[::]:6543")
                     .validator(validate_listen_address)
-                    .help("HTTP listen address"),
+                    .help("HTTP listen address (one or more), or `unix:PATH` to listen on a Unix socket"),
+                Arg::new("metrics_path")
+                    .long("metrics-path")
+                    .takes_value(true)
+                    .value_name("PATH")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_METRICS_PATH")
+                    .default_value("/metrics")
+                    .validator(validate_metrics_path)
+                    .help("Path under which the metrics are served"),
+                Arg::new("tls_cert")
+                    .long("tls-cert")
+                    .takes_value(true)
+                    .value_name("PATH")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .requires("tls_key")
+                    .env("ECS_EXPORTER_TLS_CERT")
+                    .help("Path to the PEM certificate to serve the metrics over HTTPS with"),
+                Arg::new("tls_key")
+                    .long("tls-key")
+                    .takes_value(true)
+                    .value_name("PATH")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .requires("tls_cert")
+                    .env("ECS_EXPORTER_TLS_KEY")
+                    .help("Path to the PEM private key of the TLS certificate"),
+                Arg::new("auth_username")
+                    .long("auth-username")
+                    .takes_value(true)
+                    .value_name("USERNAME")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .requires("auth_password_source")
+                    .env("ECS_EXPORTER_AUTH_USERNAME")
+                    .help("Require basic auth with this user to get the metrics"),
+                Arg::new("auth_password")
+                    .long("auth-password")
+                    .takes_value(true)
+                    .value_name("PASSWORD")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_AUTH_PASSWORD")
+                    .hide_env_values(true)
+                    .help("Basic auth password required to get the metrics"),
+                Arg::new("auth_password_file")
+                    .long("auth-password-file")
+                    .takes_value(true)
+                    .value_name("PATH")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .validator(|path| fs::read_to_string(path).map(|_| ()))
+                    .env("ECS_EXPORTER_AUTH_PASSWORD_FILE")
+                    .help("File containing the basic auth password required to get the metrics"),
+                Arg::new("service_name_include")
+                    .long("service-name-include")
+                    .takes_value(true)
+                    .value_name("REGEX")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .validator(|value| Regex::new(value).map(|_| ()))
+                    .env("ECS_EXPORTER_SERVICE_NAME_INCLUDE")
+                    .help("Only export the services whose name matches this regex"),
+                Arg::new("service_name_exclude")
+                    .long("service-name-exclude")
+                    .takes_value(true)
+                    .value_name("REGEX")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .validator(|value| Regex::new(value).map(|_| ()))
+                    .env("ECS_EXPORTER_SERVICE_NAME_EXCLUDE")
+                    .help("Don't export the services whose name matches this regex, even if included"),
+                Arg::new("service_include")
+                    .long("service-include")
+                    .takes_value(true)
+                    .value_name("FIELD")
+                    .required(false)
+                    .multiple_occurrences(true)
+                    .multiple_values(true)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_SERVICE_INCLUDE")
+                    .help("Additional fields to request when describing services, e.g. TAGS"),
+                Arg::new("instance_include")
+                    .long("instance-include")
+                    .takes_value(true)
+                    .value_name("FIELD")
+                    .required(false)
+                    .multiple_occurrences(true)
+                    .multiple_values(true)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_INSTANCE_INCLUDE")
+                    .help("Additional fields to request when describing container instances, e.g. TAGS"),
+                Arg::new("with_quotas")
+                    .long("with-quotas")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Also export the account's ECS service quotas and their usage"),
+                Arg::new("with_images")
+                    .long("with-images")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Also export the container images of each service's task definition"),
+                Arg::new("with_task_definitions")
+                    .long("with-task-definitions")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Also export the CPU and memory registered by each service's task definition"),
+                Arg::new("disabled_collectors")
+                    .long("disable-collector")
+                    .takes_value(true)
+                    .value_name("COLLECTOR")
+                    .required(false)
+                    .multiple_occurrences(true)
+                    .multiple_values(true)
+                    .possible_values(Collector::NAMES)
+                    .env("ECS_EXPORTER_DISABLED_COLLECTORS")
+                    .help("Don't scrape these kinds of resources, e.g. to save API calls"),
+                Arg::new("remote_write_url")
+                    .long("remote-write-url")
+                    .takes_value(true)
+                    .value_name("URL")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_REMOTE_WRITE_URL")
+                    .help("Prometheus remote write endpoint to push metrics to, if any"),
+                Arg::new("remote_write_interval")
+                    .long("remote-write-interval")
+                    .takes_value(true)
+                    .value_name("SECONDS")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_REMOTE_WRITE_INTERVAL")
+                    .default_value("60")
+                    .validator(|value| validate_at_least(value, 1))
+                    .help("Longest interval between two pushes to the remote write endpoint, which otherwise pushes the metrics of every scrape"),
+                Arg::new("remote_write_label")
+                    .long("remote-write-label")
+                    .takes_value(true)
+                    .value_name("NAME=VALUE")
+                    .required(false)
+                    .multiple_occurrences(true)
+                    .multiple_values(true)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_REMOTE_WRITE_LABELS")
+                    .validator(validate_label_pair)
+                    .help("External label to add to all pushed series"),
+                Arg::new("remote_write_username")
+                    .long("remote-write-username")
+                    .takes_value(true)
+                    .value_name("USERNAME")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_REMOTE_WRITE_USERNAME")
+                    .help("Basic auth user for the remote write endpoint"),
+                Arg::new("remote_write_password")
+                    .long("remote-write-password")
+                    .takes_value(true)
+                    .value_name("PASSWORD")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_REMOTE_WRITE_PASSWORD")
+                    .hide_env_values(true)
+                    .requires("remote_write_username")
+                    .help("Basic auth password for the remote write endpoint"),
+                Arg::new("remote_write_changed_only")
+                    .long("remote-write-changed-only")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Only push series whose value changed since the previous push, still pushing the others every 4 minutes so they don't go stale"),
+                Arg::new("max_stale")
+                    .long("max-stale")
+                    .takes_value(true)
+                    .value_name("SECONDS")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_MAX_STALE")
+                    .default_value("300")
+                    .validator(|value| value.parse::<u64>().map(|_| ()))
+                    .help("Maximum age of the data before /metrics returns 503, 0 to disable"),
+                Arg::new("cache_ttl")
+                    .long("cache-ttl")
+                    .takes_value(true)
+                    .value_name("SECONDS")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_CACHE_TTL")
+                    .default_value("0")
+                    .validator(|value| value.parse::<u64>().map(|_| ()))
+                    .help("Time during which a successful scrape is served again, 0 to disable"),
+                Arg::new("ha_lock")
+                    .long("ha-lock")
+                    .takes_value(true)
+                    .value_name("DYNAMODB_TABLE")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_HA_LOCK")
+                    .help("DynamoDB table holding the lease, so only one replica scrapes AWS"),
+                Arg::new("ha_lock_name")
+                    .long("ha-lock-name")
+                    .takes_value(true)
+                    .value_name("NAME")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_HA_LOCK_NAME")
+                    .default_value("aws-ecs-exporter")
+                    .help("Name of the lease shared by the replicas"),
+                Arg::new("ha_lease")
+                    .long("ha-lease")
+                    .takes_value(true)
+                    .value_name("SECONDS")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_HA_LEASE")
+                    .default_value("60")
+                    .validator(|value| validate_at_least(value, MIN_HA_LEASE))
+                    .help("How long the leader holds the lease without renewing it, at least 3 seconds as it's renewed every third of it"),
+                Arg::new("max_concurrency")
+                    .long("max-concurrency")
+                    .alias("describe-concurrency")
+                    .takes_value(true)
+                    .value_name("COUNT")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_MAX_CONCURRENCY")
+                    .default_value(&default_max_concurrency)
+                    .validator(validate_concurrency)
+                    .help("Maximum number of concurrent describe calls per cluster, also accepted as --describe-concurrency"),
+                Arg::new("cluster_concurrency")
+                    .long("cluster-concurrency")
+                    .takes_value(true)
+                    .value_name("CLUSTER=COUNT")
+                    .required(false)
+                    .multiple_occurrences(true)
+                    .multiple_values(true)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_CLUSTER_CONCURRENCY")
+                    .validator(|value| match value.split_once('=') {
+                        Some((cluster, count)) if !cluster.is_empty() => validate_concurrency(count),
+                        _ => Err("must be of the form `cluster=count`".to_string()),
+                    })
+                    .help("Override of --max-concurrency for a given cluster"),
+                Arg::new("shared_concurrency")
+                    .long("shared-concurrency")
+                    .takes_value(true)
+                    .value_name("COUNT")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_SHARED_CONCURRENCY")
+                    .validator(validate_concurrency)
+                    .help("Maximum number of concurrent describe calls across all clusters and regions"),
+                Arg::new("cluster_parallelism")
+                    .long("cluster-parallelism")
+                    .takes_value(true)
+                    .value_name("COUNT")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_CLUSTER_PARALLELISM")
+                    .default_value(&default_cluster_parallelism)
+                    .validator(validate_concurrency)
+                    .help("Maximum number of clusters scraped concurrently"),
+                Arg::new("max_retries")
+                    .long("max-retries")
+                    .takes_value(true)
+                    .value_name("COUNT")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_MAX_RETRIES")
+                    .default_value(&default_max_retries)
+                    .validator(|value| value.parse::<usize>().map(|_| ()))
+                    .help("Number of times throttled or failed ECS calls are retried, on top of the SDK's own attempts"),
+                Arg::new("describe_chunk_size")
+                    .long("describe-chunk-size")
+                    .takes_value(true)
+                    .value_name("COUNT")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_DESCRIBE_CHUNK_SIZE")
+                    .validator(validate_concurrency)
+                    .help("Maximum number of services or instances per describe call, capped to what the API accepts"),
+                Arg::new("scrape_timeout")
+                    .long("scrape-timeout")
+                    .takes_value(true)
+                    .value_name("SECONDS")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_SCRAPE_TIMEOUT")
+                    .default_value(&default_scrape_timeout)
+                    .validator(|value| value.parse::<u64>().map(|_| ()))
+                    .help("How long scraping each kind of resource of a cluster may take"),
+                Arg::new("aws_connect_timeout")
+                    .long("aws-connect-timeout")
+                    .takes_value(true)
+                    .value_name("SECONDS")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_AWS_CONNECT_TIMEOUT")
+                    .default_value(DEFAULT_AWS_CONNECT_TIMEOUT)
+                    .validator(|value| value.parse::<u64>().map(|_| ()))
+                    .help("How long connecting to the AWS APIs may take"),
+                Arg::new("aws_operation_timeout")
+                    .long("aws-operation-timeout")
+                    .takes_value(true)
+                    .value_name("SECONDS")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_AWS_OPERATION_TIMEOUT")
+                    .default_value(DEFAULT_AWS_OPERATION_TIMEOUT)
+                    .validator(|value| value.parse::<u64>().map(|_| ()))
+                    .help("How long a single AWS API call may take, including the SDK's own retries"),
+                Arg::new("ready_window")
+                    .long("ready-window")
+                    .takes_value(true)
+                    .value_name("SECONDS")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_READY_WINDOW")
+                    .validator(|value| value.parse::<u64>().map(|_| ()))
+                    .help("How recent a successful scrape must be for /ready to succeed [default: twice the scrape timeout]"),
+                Arg::new("counted_attributes")
+                    .long("count-instances-with-attribute")
+                    .takes_value(true)
+                    .value_name("NAME[=VALUE]")
+                    .required(false)
+                    .multiple_occurrences(true)
+                    .multiple_values(true)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_COUNTED_ATTRIBUTES")
+                    .help("Count the active instances having this attribute, optionally with this value"),
+                Arg::new("with_managed_agents")
+                    .long("with-managed-agents")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Also export the status of the tasks' managed agents, e.g. for ECS Exec"),
+                Arg::new("with_tasks")
+                    .long("with-tasks")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Also export one series per task, with its status and service"),
+                Arg::new("with_stopped_tasks")
+                    .long("with-stopped-tasks")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Also export the number of recently stopped tasks, by service and reason they stopped"),
+                Arg::new("max_stopped_tasks")
+                    .long("max-stopped-tasks")
+                    .takes_value(true)
+                    .value_name("COUNT")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_MAX_STOPPED_TASKS")
+                    .default_value(&default_max_stopped_tasks)
+                    .validator(|value| value.parse::<usize>().map(|_| ()))
+                    .help("Maximum number of stopped tasks described per cluster, the most recent ones"),
+                Arg::new("cloudwatch_namespace")
+                    .long("cloudwatch-namespace")
+                    .takes_value(true)
+                    .value_name("NAMESPACE")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_CLOUDWATCH_NAMESPACE")
+                    .help("Also push the cluster and service task counts to CloudWatch under this namespace, as custom metrics charged by CloudWatch"),
+                Arg::new("cluster_label")
+                    .long("cluster-label-name")
+                    .takes_value(true)
+                    .value_name("LABEL")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_CLUSTER_LABEL_NAME")
+                    .default_value(DEFAULT_CLUSTER_LABEL)
+                    .validator(validate_cluster_label)
+                    .help("Name of the label identifying the cluster in all metrics"),
+                Arg::new("hide_empty_clusters")
+                    .long("hide-empty-clusters")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Omit all series for clusters where no resources were found"),
+                Arg::new("emit_cluster_arn")
+                    .long("emit-cluster-arn-label")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Add the cluster ARN as a `cluster_arn` label to all cluster metrics"),
+                Arg::new("sts_check")
+                    .long("readonly-sts-check")
+                    .takes_value(false)
+                    .required(false)
+                    .requires("role")
+                    .help("Check at startup that the role can be assumed, by calling GetCallerIdentity"),
+                Arg::new("with_target_health")
+                    .long("with-target-health")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Also export the health of the services' load balancer targets"),
+                Arg::new("instance_ip_label")
+                    .long("instance-ip-label")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Add the container instance's private IP as a `private_ip` label"),
+                Arg::new("exporter_name")
+                    .long("exporter-name")
+                    .takes_value(true)
+                    .value_name("NAME")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_NAME")
+                    .validator_regex(&metric_name_re, "must be a valid Prometheus metric name")
+                    .help("Prefix of the metrics describing the exporter itself, such as `_info`, by default the metrics prefix followed by `_exporter`"),
+                Arg::new("metrics_prefix")
+                    .long("metrics-prefix")
+                    .takes_value(true)
+                    .value_name("PREFIX")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("ECS_EXPORTER_METRICS_PREFIX")
+                    .validator_regex(&metric_name_re, "must be a valid Prometheus metric name")
+                    .help("Prefix of the names of all the metrics, `aws_ecs` by default. When given, the `http_requests` metric is prefixed with the exporter name too"),
+                Arg::new("log_format")
+                    .long("log-format")
+                    .takes_value(true)
+                    .value_name("FORMAT")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .possible_values(["text", "json"])
+                    .env("ECS_EXPORTER_LOG_FORMAT")
+                    .default_value("text")
+                    .help("Format of the logs"),
+                Arg::new("log_level")
+                    .long("log-level")
+                    .takes_value(true)
+                    .value_name("FILTER")
+                    .required(false)
+                    .multiple_occurrences(false)
+                    .multiple_values(false)
+                    .forbid_empty_values(true)
+                    .env("RUST_LOG")
+                    .default_value("info")
+                    .help("Log level or filter directives, e.g. `warn,aws_ecs_exporter=debug`"),
+                Arg::new("fail_fast")
+                    .long("fail-fast")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Exit if the AWS clients can't be set up, instead of serving failed scrapes"),
+                Arg::new("once")
+                    .long("once")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Scrape a single time, print the metrics and exit instead of serving them"),
+                Arg::new("with_capacity_shortfall")
+                    .long("with-capacity-shortfall")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Estimate whether the services' missing tasks fit on the remaining instance resources"),
+                Arg::new("omit_registered_resources")
+                    .long("omit-registered-resources")
+                    .takes_value(false)
+                    .required(false)
+                    .help("Don't export the resources registered by the instances, only the remaining ones"),
             ])
-            .get_matches();
+            .group(
+                ArgGroup::new("auth_password_source")
+                    .args(&["auth_password", "auth_password_file"])
+                    .requires("auth_username"),
+            );
+        #[cfg(feature = "bench")]
+        let app = app.args(&sample::args());
+        let mut app = app;
+
+        let args: Vec<OsString> = std::env::args_os().collect();
+        let args = match config_file_path(&args) {
+            Some(path) => {
+                let file_args = parse_config_file(&path)
+                    .and_then(|file_config| config_file_args(&app, file_config, &args));
+                match file_args {
+                    // The file's arguments come first, so that the usual errors still apply
+                    Ok(file_args) => args[..1]
+                        .iter()
+                        .cloned()
+                        .chain(file_args.into_iter().map(OsString::from))
+                        .chain(args[1..].iter().cloned())
+                        .collect(),
+                    Err(err) => app
+                        .error(
+                            ErrorKind::InvalidValue,
+                            format!("Invalid configuration file `{}`: {}", path, err),
+                        )
+                        .exit(),
+                }
+            }
+            None => args,
+        };
+        let matches = app.get_matches_from(args);
+
+        let any_cluster_role = matches
+            .values_of("clusters")
+            .into_iter()
+            .flatten()
+            .any(|value| value.contains("role="));
+        // Logging isn't set up yet, so warnings are only collected
+        let mut warnings = vec![];
+        if !matches.is_present("role") && !any_cluster_role {
+            for ignored in ["external_id", "session_name"] {
+                // The web identity role is assumed with the session name too
+                let used =
+                    ignored == "session_name" && matches.is_present("web_identity_token_file");
+                if matches.is_present(ignored) && !used {
+                    warnings.push(format!(
+                        "Ignoring `{}` as no role is to be assumed",
+                        ignored
+                    ));
+                }
+            }
+        }
 
         Self {
-            cluster_names: matches.values_of_t_or_exit("clusters"),
+            clusters: matches
+                .values_of("clusters")
+                .into_iter()
+                .flatten()
+                .filter_map(|value| parse_cluster(value).ok())
+                .collect(),
+            all_clusters: matches.is_present("all_clusters"),
+            cluster_tags: matches
+                .values_of("cluster_tags")
+                .into_iter()
+                .flatten()
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            tag_labels: matches
+                .values_of("tag_labels")
+                .into_iter()
+                .flatten()
+                .map(String::from)
+                .collect(),
             aws_role: matches.value_of("role").map(String::from),
-            listen_address: matches.value_of_t_or_exit("listen"),
+            external_id: matches.value_of("external_id").map(String::from),
+            session_name: matches.value_of("session_name").map(String::from),
+            sts_endpoint: matches.value_of("sts_endpoint").map(String::from),
+            web_identity: matches
+                .value_of("web_identity_token_file")
+                .zip(matches.value_of("web_identity_role"))
+                .map(|(token_file, role)| WebIdentityConfig {
+                    token_file: token_file.to_string(),
+                    role: role.to_string(),
+                }),
+            static_credentials: matches
+                .value_of("access_key_id")
+                .zip(matches.value_of("secret_access_key"))
+                .map(|(key_id, secret)| {
+                    Credentials::new(
+                        key_id,
+                        secret,
+                        matches.value_of("session_token").map(String::from),
+                        None,
+                        "ecs-exporter-static",
+                    )
+                }),
+            listen_addresses: matches.values_of_t_or_exit("listen"),
+            metrics_path: matches.value_of("metrics_path").unwrap().to_string(),
+            tls: matches
+                .value_of("tls_cert")
+                .zip(matches.value_of("tls_key"))
+                .map(|(cert, key)| TlsConfig {
+                    cert: cert.to_string(),
+                    key: key.to_string(),
+                }),
+            basic_auth: matches
+                .value_of("auth_username")
+                .map(|username| BasicAuthConfig {
+                    username: username.to_string(),
+                    password: match matches.value_of("auth_password_file") {
+                        Some(path) => fs::read_to_string(path)
+                            .expect("The password file should have been validated")
+                            .trim_end()
+                            .to_string(),
+                        None => matches.value_of("auth_password").unwrap().to_string(),
+                    },
+                }),
             region: matches
                 .value_of("region")
                 .map(String::from)
                 .map(Region::new),
             app_version: crate_version!().to_string(),
+            service_include: parse_include_fields(
+                matches.values_of("service_include"),
+                |field| !matches!(field, ServiceField::Unknown(_)),
+                &mut warnings,
+            ),
+            service_name_include: matches
+                .value_of("service_name_include")
+                .map(|value| Regex::new(value).unwrap()),
+            service_name_exclude: matches
+                .value_of("service_name_exclude")
+                .map(|value| Regex::new(value).unwrap()),
+            instance_include: parse_include_fields(
+                matches.values_of("instance_include"),
+                |field| !matches!(field, ContainerInstanceField::Unknown(_)),
+                &mut warnings,
+            ),
+            with_quotas: matches.is_present("with_quotas"),
+            with_images: matches.is_present("with_images"),
+            with_task_definitions: matches.is_present("with_task_definitions"),
+            disabled_collectors: matches
+                .values_of("disabled_collectors")
+                .into_iter()
+                .flatten()
+                .filter_map(Collector::from_name)
+                .collect(),
+            remote_write: matches
+                .value_of("remote_write_url")
+                .map(|url| RemoteWriteConfig {
+                    url: url.to_string(),
+                    interval: Duration::from_secs(
+                        matches.value_of_t_or_exit("remote_write_interval"),
+                    ),
+                    external_labels: matches
+                        .values_of("remote_write_label")
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|pair| pair.split_once('='))
+                        .map(|(name, value)| (name.to_string(), value.to_string()))
+                        .collect(),
+                    username: matches.value_of("remote_write_username").map(String::from),
+                    password: matches.value_of("remote_write_password").map(String::from),
+                    changed_only: matches.is_present("remote_write_changed_only"),
+                }),
+            max_stale: Some(matches.value_of_t_or_exit("max_stale"))
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs),
+            cache_ttl: Some(matches.value_of_t_or_exit("cache_ttl"))
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs),
+            ha_lock: matches.value_of("ha_lock").map(|table_name| HaLockConfig {
+                table_name: table_name.to_string(),
+                lock_name: matches.value_of("ha_lock_name").unwrap().to_string(),
+                lease: Duration::from_secs(matches.value_of_t_or_exit("ha_lease")),
+            }),
+            max_concurrency: matches.value_of_t_or_exit("max_concurrency"),
+            cluster_concurrency: matches
+                .values_of("cluster_concurrency")
+                .into_iter()
+                .flatten()
+                .filter_map(|pair| pair.split_once('='))
+                .filter_map(|(cluster, count)| Some((cluster.to_string(), count.parse().ok()?)))
+                .collect(),
+            shared_concurrency: matches.value_of_t("shared_concurrency").ok(),
+            cluster_parallelism: matches.value_of_t_or_exit("cluster_parallelism"),
+            describe_chunk_size: matches.value_of_t("describe_chunk_size").ok(),
+            max_retries: matches.value_of_t_or_exit("max_retries"),
+            scrape_timeout: Duration::from_secs(matches.value_of_t_or_exit("scrape_timeout")),
+            aws_connect_timeout: Duration::from_secs(
+                matches.value_of_t_or_exit("aws_connect_timeout"),
+            ),
+            aws_operation_timeout: Duration::from_secs(
+                matches.value_of_t_or_exit("aws_operation_timeout"),
+            ),
+            ready_window: matches
+                .value_of_t("ready_window")
+                .map(Duration::from_secs)
+                .unwrap_or_else(|_| {
+                    Duration::from_secs(matches.value_of_t_or_exit("scrape_timeout")) * 2
+                }),
+            counted_attributes: matches
+                .values_of("counted_attributes")
+                .into_iter()
+                .flatten()
+                .map(String::from)
+                .collect(),
+            with_managed_agents: matches.is_present("with_managed_agents"),
+            with_tasks: matches.is_present("with_tasks"),
+            max_stopped_tasks: matches
+                .is_present("with_stopped_tasks")
+                .then(|| matches.value_of_t_or_exit("max_stopped_tasks")),
+            cloudwatch_namespace: matches.value_of("cloudwatch_namespace").map(String::from),
+            cluster_label: matches.value_of("cluster_label").unwrap().to_string(),
+            hide_empty_clusters: matches.is_present("hide_empty_clusters"),
+            emit_cluster_arn: matches.is_present("emit_cluster_arn"),
+            sts_check: matches.is_present("sts_check"),
+            no_imds: matches.is_present("no_imds"),
+            with_target_health: matches.is_present("with_target_health"),
+            instance_ip_label: matches.is_present("instance_ip_label"),
+            exporter_name: matches
+                .value_of("exporter_name")
+                .map(String::from)
+                .unwrap_or_else(|| {
+                    format!(
+                        "{}_exporter",
+                        matches
+                            .value_of("metrics_prefix")
+                            .unwrap_or(DEFAULT_METRICS_PREFIX)
+                    )
+                }),
+            metrics_prefix: matches.value_of("metrics_prefix").map(String::from),
+            fail_fast: matches.is_present("fail_fast"),
+            once: matches.is_present("once"),
+            log_format: match matches.value_of("log_format").unwrap() {
+                "text" => LogFormat::Text,
+                "json" => LogFormat::Json,
+                format => unreachable!("Log format `{}` should have been rejected", format),
+            },
+            log_level: matches.value_of("log_level").unwrap().to_string(),
+            warnings,
+            with_capacity_shortfall: matches.is_present("with_capacity_shortfall"),
+            omit_registered_resources: matches.is_present("omit_registered_resources"),
+            #[cfg(feature = "bench")]
+            sample: matches
+                .value_of_t("sample_clusters")
+                .ok()
+                .map(|clusters| SampleConfig {
+                    clusters,
+                    services: matches.value_of_t_or_exit("sample_services"),
+                    instances: matches.value_of_t_or_exit("sample_instances"),
+                }),
+        }
+    }
+}
+
+/// Parses a cluster given either as its name or as `name=NAME[,role=ROLE_ARN][,region=REGION]`
+fn parse_cluster(value: &str) -> Result<ClusterConfig, String> {
+    if !value.contains('=') {
+        return Ok(ClusterConfig {
+            name: value.to_string(),
+            role: None,
+            region: None,
+        });
+    }
+
+    let mut name = None;
+    let mut role = None;
+    let mut region = None;
+    let role_arn_re = Regex::new(ROLE_ARN_PATTERN).unwrap();
+    for pair in value.split(',') {
+        match pair.split_once('=') {
+            Some(("name", value)) if !value.is_empty() => name = Some(value.to_string()),
+            Some(("role", value)) if role_arn_re.is_match(value) => role = Some(value.to_string()),
+            Some(("region", value)) if !value.is_empty() => region = Some(value.to_string()),
+            _ => return Err(format!("invalid cluster setting `{}`", pair)),
         }
     }
+    Ok(ClusterConfig {
+        name: name.ok_or("missing cluster name, expected `name=NAME,role=ROLE_ARN`")?,
+        role,
+        region,
+    })
+}
+
+/// Returns the configuration file given on the command line or through the environment
+///
+/// This has to be known before the arguments are parsed, as the file provides some of them.
+fn config_file_path(args: &[OsString]) -> Option<String> {
+    let mut args = args.iter().skip(1).filter_map(|arg| arg.to_str());
+    while let Some(arg) = args.next() {
+        if arg == "--config-file" {
+            return args.next().map(String::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config-file=") {
+            return Some(path.to_string());
+        }
+    }
+    std::env::var(CONFIG_FILE_ENV)
+        .ok()
+        .filter(|path| !path.is_empty())
+}
+
+fn parse_config_file(path: &str) -> Result<FileConfig, String> {
+    let content = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    if path.ends_with(".toml") {
+        toml::from_str(&content).map_err(|err| err.to_string())
+    } else {
+        serde_yaml::from_str(&content).map_err(|err| err.to_string())
+    }
+}
+
+/// Turns the settings of the configuration file into arguments
+///
+/// Those also given on the command line or through the environment are left out, so that the
+/// latter take precedence.
+fn config_file_args(
+    app: &App,
+    file_config: FileConfig,
+    cli_args: &[OsString],
+) -> Result<Vec<String>, String> {
+    let is_overridden = |arg: &Arg| {
+        let long = format!("--{}", arg.get_long().unwrap_or_default());
+        cli_args
+            .iter()
+            .filter_map(|cli_arg| cli_arg.to_str())
+            .any(|cli_arg| {
+                cli_arg == long
+                    || cli_arg
+                        .strip_prefix(&long)
+                        .is_some_and(|rest| rest.starts_with('='))
+            })
+            || arg
+                .get_env()
+                .is_some_and(|env| std::env::var_os(env).is_some())
+    };
+    let find_arg = |id: &str| app.get_arguments().find(|arg| arg.get_name() == id);
+
+    let mut result = vec![];
+    let clusters_overridden = ["clusters", "all_clusters"]
+        .iter()
+        .filter_map(|id| find_arg(id))
+        .any(&is_overridden);
+    for (index, cluster) in file_config.clusters.into_iter().enumerate() {
+        let mut value = format!("name={}", cluster.name);
+        if let Some(role) = cluster.role {
+            value.push_str(&format!(",role={}", role));
+        }
+        if let Some(region) = cluster.region {
+            value.push_str(&format!(",region={}", region));
+        }
+        parse_cluster(&value).map_err(|err| format!("clusters[{}]: {}", index, err))?;
+        if !clusters_overridden {
+            result.push(format!("--cluster={}", value));
+        }
+    }
+
+    for (key, value) in file_config.options {
+        let arg = app
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()) && arg.get_name() != "config_file")
+            .ok_or_else(|| format!("unknown key `{}`", key))?;
+        if is_overridden(arg) {
+            continue;
+        }
+        let long = format!("--{}", key);
+        match (value, arg.is_set(ArgSettings::TakesValue)) {
+            (FileValue::Flag(true), false) => result.push(long),
+            (FileValue::Flag(false), false) => {}
+            (_, false) => return Err(format!("`{}` must be either true or false", key)),
+            (FileValue::Flag(_), true) => return Err(format!("`{}` expects a value", key)),
+            (FileValue::List(values), true) => {
+                if !arg.is_set(ArgSettings::MultipleOccurrences) && values.len() != 1 {
+                    return Err(format!("`{}` expects a single value", key));
+                }
+                result.extend(
+                    values
+                        .into_iter()
+                        .map(|value| format!("{}={}", long, value)),
+                );
+            }
+            (value, true) => result.push(format!("{}={}", long, value)),
+        }
+    }
+    Ok(result)
 }
 
 fn validate_listen_address(value: &str) -> Result<(), String> {
-    value
-        .parse::<SocketAddr>()
-        .map_err(|err| format!("{}", err))
-        .map(|_| ())
+    value.parse::<ListenAddress>().map(|_| ())
+}
+
+fn validate_metrics_path(value: &str) -> Result<(), String> {
+    let path = value.strip_prefix('/').unwrap_or(value);
+    let first_segment = path.split('/').next().unwrap_or_default();
+    if path.split('/').any(str::is_empty) {
+        Err("must not be empty nor contain empty segments".to_string())
+    } else if RESERVED_PATHS.contains(&first_segment) {
+        // These routes are matched first, including any path under them
+        Err(format!(
+            "`/{}` is reserved for the exporter's other endpoints",
+            first_segment
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_cluster_label(value: &str) -> Result<(), String> {
+    if !Regex::new(LABEL_NAME_PATTERN).unwrap().is_match(value) {
+        Err("must be a valid Prometheus label name, not starting with `__`".to_string())
+    } else if RESERVED_LABEL_NAMES.contains(&value) || value.starts_with("tag_") {
+        Err(format!("`{}` is already the name of another label", value))
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_concurrency(value: &str) -> Result<(), String> {
+    match value.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(()),
+        Ok(_) => Err("must be at least 1".to_string()),
+        Err(err) => Err(format!("{}", err)),
+    }
+}
+
+fn validate_at_least(value: &str, min: u64) -> Result<(), String> {
+    match value.parse::<u64>() {
+        Ok(number) if number >= min => Ok(()),
+        Ok(_) => Err(format!("must be at least {}", min)),
+        Err(err) => Err(format!("{}", err)),
+    }
+}
+
+fn validate_label_pair(value: &str) -> Result<(), String> {
+    match value.split_once('=') {
+        Some((name, _)) if !name.is_empty() => Ok(()),
+        _ => Err("must be of the form `name=value`".to_string()),
+    }
+}
+
+/// Converts the given values to SDK enum variants, dropping those the SDK doesn't know about
+fn parse_include_fields<'a, T: From<&'a str>>(
+    values: Option<impl Iterator<Item = &'a str>>,
+    is_known: impl Fn(&T) -> bool,
+    warnings: &mut Vec<String>,
+) -> Vec<T> {
+    values
+        .into_iter()
+        .flatten()
+        .filter_map(|value| {
+            let field = T::from(value);
+            if is_known(&field) {
+                Some(field)
+            } else {
+                warnings.push(format!("Ignoring unknown include field `{}`", value));
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn plain_cluster_has_no_role() {
+        assert_eq!(
+            parse_cluster("prod"),
+            Ok(ClusterConfig {
+                name: "prod".to_string(),
+                role: None,
+                region: None,
+            })
+        );
+    }
+
+    #[test]
+    fn cluster_with_role_is_parsed() {
+        assert_eq!(
+            parse_cluster("name=prod,role=arn:aws:iam::123456789012:role/ecs-read"),
+            Ok(ClusterConfig {
+                name: "prod".to_string(),
+                role: Some("arn:aws:iam::123456789012:role/ecs-read".to_string()),
+                region: None,
+            })
+        );
+    }
+
+    #[test]
+    fn cluster_with_region_is_parsed() {
+        assert_eq!(
+            parse_cluster("name=prod,region=eu-west-1"),
+            Ok(ClusterConfig {
+                name: "prod".to_string(),
+                role: None,
+                region: Some("eu-west-1".to_string()),
+            })
+        );
+    }
+
+    fn config_file_test_app() -> App<'static> {
+        App::new("test").args(&[
+            Arg::new("clusters")
+                .long("cluster")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            Arg::new("all_clusters").long("all-clusters"),
+            Arg::new("max_retries")
+                .long("max-retries")
+                .takes_value(true),
+            Arg::new("counted_attributes")
+                .long("count-attribute")
+                .takes_value(true)
+                .multiple_occurrences(true),
+            Arg::new("with_tasks").long("with-tasks"),
+        ])
+    }
+
+    fn cli_args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn yaml_and_toml_config_files_give_the_same_arguments() {
+        let yaml: FileConfig = serde_yaml::from_str(
+            "clusters:\n  - name: prod\n    region: eu-west-1\nmax-retries: 5\ncount-attribute: [a, b]\nwith-tasks: true\n",
+        )
+        .unwrap();
+        let toml: FileConfig = toml::from_str(
+            "max-retries = 5\ncount-attribute = [\"a\", \"b\"]\nwith-tasks = true\n\n[[clusters]]\nname = \"prod\"\nregion = \"eu-west-1\"\n",
+        )
+        .unwrap();
+        let expected = vec![
+            "--cluster=name=prod,region=eu-west-1",
+            "--count-attribute=a",
+            "--count-attribute=b",
+            "--max-retries=5",
+            "--with-tasks",
+        ];
+        let app = config_file_test_app();
+        let args = cli_args(&["test"]);
+        assert_eq!(config_file_args(&app, yaml, &args).unwrap(), expected);
+        assert_eq!(config_file_args(&app, toml, &args).unwrap(), expected);
+    }
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        let file_config: FileConfig =
+            serde_yaml::from_str("clusters:\n  - name: prod\nmax-retries: 5\n").unwrap();
+        let args = cli_args(&["test", "--all-clusters", "--max-retries=1"]);
+        assert_eq!(
+            config_file_args(&config_file_test_app(), file_config, &args).unwrap(),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn invalid_config_file_keys_are_reported() {
+        let app = config_file_test_app();
+        let args = cli_args(&["test"]);
+        let unknown: FileConfig = serde_yaml::from_str("max-retry: 5\n").unwrap();
+        assert!(config_file_args(&app, unknown, &args)
+            .unwrap_err()
+            .contains("`max-retry`"));
+        let not_a_flag: FileConfig = serde_yaml::from_str("with-tasks: yes please\n").unwrap();
+        assert!(config_file_args(&app, not_a_flag, &args)
+            .unwrap_err()
+            .contains("`with-tasks`"));
+        let invalid_role: FileConfig =
+            serde_yaml::from_str("clusters:\n  - name: prod\n    role: ecs-read\n").unwrap();
+        assert!(config_file_args(&app, invalid_role, &args)
+            .unwrap_err()
+            .starts_with("clusters[0]"));
+        assert!(
+            serde_yaml::from_str::<FileConfig>("clusters:\n  - name: prod\n    zone: a\n")
+                .unwrap_err()
+                .to_string()
+                .contains("zone")
+        );
+    }
+
+    #[test]
+    fn cluster_with_invalid_role_is_rejected() {
+        assert!(parse_cluster("name=prod,role=ecs-read").is_err());
+        assert!(parse_cluster("role=arn:aws:iam::123456789012:role/ecs-read").is_err());
+        assert!(parse_cluster("name=prod,zone=eu-west-1a").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_listen_address_is_parsed() {
+        assert_eq!(
+            "unix:/run/ecs-exporter.sock".parse(),
+            Ok(ListenAddress::Unix(PathBuf::from("/run/ecs-exporter.sock")))
+        );
+        assert!(validate_listen_address("unix:").is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn valid_listen_address_is_accepted(address in any::<SocketAddr>()) {
+            prop_assert_eq!(validate_listen_address(&address.to_string()), Ok(()));
+        }
+
+        #[test]
+        fn malformed_listen_address_is_rejected(address in "[^0-9]*") {
+            prop_assert_eq!(
+                validate_listen_address(&address),
+                Err("invalid socket address syntax".to_string())
+            );
+        }
+
+        #[test]
+        fn role_arn_is_accepted(
+            partition in "aws|aws-cn|aws-us-gov",
+            account in "[0-9]{12}",
+            path in prop::collection::vec("[A-Za-z0-9_-]{1,16}", 0..3),
+            name in "[A-Za-z0-9+=,.@_-]{1,64}",
+        ) {
+            let role_re = Regex::new(ROLE_ARN_PATTERN).unwrap();
+            let mut resource = path;
+            resource.push(name);
+            let arn = format!(
+                "arn:{}:iam::{}:role/{}",
+                partition,
+                account,
+                resource.join("/")
+            );
+            prop_assert!(role_re.is_match(&arn), "{} should be accepted", arn);
+        }
+
+        #[test]
+        fn non_role_arn_is_rejected(
+            account in "[0-9]{12}",
+            resource_type in "user|group|policy|instance-profile",
+            name in "[A-Za-z0-9+=,.@_-]{1,64}",
+        ) {
+            let role_re = Regex::new(ROLE_ARN_PATTERN).unwrap();
+            let arn = format!("arn:aws:iam::{}:{}/{}", account, resource_type, name);
+            prop_assert!(!role_re.is_match(&arn), "{} should be rejected", arn);
+        }
+
+        #[test]
+        fn role_arn_with_trailing_junk_is_rejected(
+            account in "[0-9]{12}",
+            name in "[A-Za-z0-9_-]{1,64}",
+            junk in "[ :*#?]{1,8}",
+        ) {
+            let role_re = Regex::new(ROLE_ARN_PATTERN).unwrap();
+            let arn = format!("arn:aws:iam::{}:role/{}{}", account, name, junk);
+            prop_assert!(!role_re.is_match(&arn), "{} should be rejected", arn);
+        }
+
+        #[test]
+        fn role_arn_in_unknown_partition_is_rejected(
+            partition in "aws-[a-z]{1,8}",
+            account in "[0-9]{12}",
+            name in "[A-Za-z0-9_-]{1,64}",
+        ) {
+            prop_assume!(partition != "aws-cn" && partition != "aws-us-gov");
+            let role_re = Regex::new(ROLE_ARN_PATTERN).unwrap();
+            let arn = format!("arn:{}:iam::{}:role/{}", partition, account, name);
+            prop_assert!(!role_re.is_match(&arn), "{} should be rejected", arn);
+        }
+    }
+
+    #[test]
+    fn cluster_label_must_not_collide_with_other_labels() {
+        assert_eq!(validate_cluster_label("ecs_cluster"), Ok(()));
+        assert_eq!(validate_cluster_label(DEFAULT_CLUSTER_LABEL), Ok(()));
+        for name in [
+            "service_name",
+            "instance_id",
+            "scraped_resource",
+            "tag_team",
+        ] {
+            assert!(validate_cluster_label(name).is_err(), "{}", name);
+        }
+        assert!(validate_cluster_label("__cluster").is_err());
+    }
+
+    #[test]
+    fn numbers_below_the_minimum_are_rejected() {
+        assert_eq!(validate_at_least("3", MIN_HA_LEASE), Ok(()));
+        assert!(validate_at_least("0", MIN_HA_LEASE).is_err());
+        assert!(validate_at_least("-1", MIN_HA_LEASE).is_err());
+    }
+
+    #[test]
+    fn metrics_path_must_not_shadow_other_endpoints() {
+        assert_eq!(validate_metrics_path("/v1/metrics"), Ok(()));
+        assert_eq!(validate_metrics_path("/statuses"), Ok(()));
+        for path in [
+            "/status",
+            "ready",
+            "/ready/metrics",
+            "/status/metrics",
+            "/refresh",
+        ] {
+            assert!(validate_metrics_path(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn role_arn_is_accepted_in_each_partition() {
+        let role_re = Regex::new(ROLE_ARN_PATTERN).unwrap();
+        for partition in ["aws", "aws-cn", "aws-us-gov"] {
+            let arn = format!("arn:{}:iam::123456789012:role/path/Name", partition);
+            assert!(role_re.is_match(&arn), "{} should be accepted", arn);
+        }
+    }
 }
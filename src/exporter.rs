@@ -1,37 +1,329 @@
-use crate::config::TlsConfig;
+use crate::config::{BasicAuthConfig, ListenAddress, TlsConfig};
+use crate::openmetrics::{self, OpenMetricsEncoder, OPENMETRICS_FORMAT};
 use async_trait::async_trait;
+use aws_types::credentials::{ProvideCredentials, SharedCredentialsProvider};
+use color_eyre::eyre::{eyre, WrapErr};
 use color_eyre::Result;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::future::{self, BoxFuture, Shared};
+use futures::{stream, FutureExt, StreamExt};
+use prometheus::proto::MetricFamily;
 use prometheus::{
-    gather, opts, register, register_int_gauge_vec, Encoder, IntCounterVec, Registry, TextEncoder,
+    gather, opts, register, register_gauge, register_gauge_vec, register_int_counter,
+    register_int_gauge, register_int_gauge_vec, Encoder, Gauge, IntCounter, IntCounterVec,
+    IntGauge, Registry, TextEncoder, TEXT_FORMAT,
 };
 use std::convert::Infallible;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use tracing::warn;
-use warp::{Filter, Reply};
+use std::io::{self, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tracing::{info, warn};
+use warp::filters::BoxedFilter;
+use warp::http::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, WWW_AUTHENTICATE};
+use warp::http::{Method, Response, StatusCode};
+use warp::hyper::body::HttpBody;
+use warp::hyper::Body;
+use warp::path::FullPath;
+use warp::{Filter, Rejection, Reply};
+
+/// Default time during which a successful scrape keeps the exporter ready
+const DEFAULT_READY_WINDOW: Duration = Duration::from_secs(60);
 
 #[async_trait]
 pub trait Scraper: Send + Sync {
     async fn scrape(&self) -> Result<Registry>;
+
+    /// Scrapes even if a recent result could be served instead, e.g. on an on-demand refresh
+    async fn refresh(&self) -> Result<Registry> {
+        self.scrape().await
+    }
+
+    /// Returns when the registry of the latest successful scrape was actually scraped
+    ///
+    /// Only scrapers serving earlier registries again, such as the cache, need to tell.
+    async fn scraped_at(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// Whether another replica is the one scraping, so that this one has nothing to serve
+    fn is_standby(&self) -> bool {
+        false
+    }
+}
+
+/// Stands in for the actual scraper when it couldn't be set up, so that every scrape fails
+pub struct FailingScraper {
+    reason: String,
+}
+
+impl FailingScraper {
+    pub fn new(reason: impl Into<String>) -> Self {
+        Self {
+            reason: reason.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl Scraper for FailingScraper {
+    async fn scrape(&self) -> Result<Registry> {
+        Err(eyre!("Exporter failed to start: {}", self.reason))
+    }
+}
+
+/// Serves the last successful scrape again as long as it's younger than the TTL
+///
+/// Concurrent scrapes wait for the one in progress instead of also calling the scraper.
+pub struct CachingScraper {
+    scraper: Arc<dyn Scraper>,
+    ttl: Duration,
+    cache: tokio::sync::Mutex<Option<(Instant, Registry)>>,
+}
+
+impl CachingScraper {
+    pub fn new(scraper: Arc<dyn Scraper>, ttl: Duration) -> Self {
+        Self {
+            scraper,
+            ttl,
+            cache: tokio::sync::Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl Scraper for CachingScraper {
+    async fn scrape(&self) -> Result<Registry> {
+        let mut cache = self.cache.lock().await;
+        if let Some((scraped_at, registry)) = cache.as_ref() {
+            if scraped_at.elapsed() < self.ttl {
+                return Ok(registry.clone());
+            }
+        }
+        let registry = self.scraper.scrape().await?;
+        *cache = Some((Instant::now(), registry.clone()));
+        Ok(registry)
+    }
+
+    async fn refresh(&self) -> Result<Registry> {
+        let mut cache = self.cache.lock().await;
+        let registry = self.scraper.refresh().await?;
+        *cache = Some((Instant::now(), registry.clone()));
+        Ok(registry)
+    }
+
+    async fn scraped_at(&self) -> Instant {
+        let cache = self.cache.lock().await;
+        cache
+            .as_ref()
+            .map_or_else(Instant::now, |(scraped_at, _)| *scraped_at)
+    }
+
+    fn is_standby(&self) -> bool {
+        self.scraper.is_standby()
+    }
+}
+
+/// Details of the exporter's build, exposed as labels of its info metric
+pub struct BuildInfo<'a> {
+    pub version: &'a str,
+    pub git_commit: &'a str,
+    pub build_date: &'a str,
+    pub rust_version: &'a str,
 }
 
 pub struct Exporter {
-    socket_address: SocketAddr,
+    listen_addresses: Vec<ListenAddress>,
     tls_config: Option<TlsConfig>,
     scraper: Arc<dyn Scraper>, // This does the actual metric collection
     exporter_metrics: Arc<IntCounterVec>, // Metrics about the exporter itself
+    data_age: Gauge,
+    max_stale: Option<Duration>,
+    in_flight: Arc<InFlight>,
+    credentials_expiry: Option<Arc<CredentialsExpiry>>,
+    startup_error: IntGauge,
+    metrics_path: String,
+    ready_window: Duration,
+    // Expected value of the `Authorization` header of metrics requests
+    authorization: Option<String>,
+    exporter_name: String,
+    exporter_version: String,
+}
+
+/// Tracks how old the served data is
+struct Freshness {
+    last_success: Mutex<Instant>,
+    max_stale: Option<Duration>,
+    data_age: Gauge,
+}
+
+impl Freshness {
+    /// Records the outcome of a scrape and returns the age of the most recent successful one
+    ///
+    /// A successful scrape gives when its data was actually scraped, earlier for cached data.
+    fn update(&self, scraped_at: Option<Instant>) -> Duration {
+        let mut last_success = self.last_success.lock().unwrap();
+        if let Some(scraped_at) = scraped_at {
+            *last_success = scraped_at.max(*last_success);
+        }
+        let age = last_success.elapsed();
+        self.data_age.set(age.as_secs_f64());
+        age
+    }
+
+    fn is_too_stale(&self, age: Duration) -> bool {
+        self.max_stale.is_some_and(|max_stale| age > max_stale)
+    }
+}
+
+/// Tracks whether a recent scrape succeeded, for readiness probes
+struct Readiness {
+    last_ready: Mutex<Option<Instant>>,
+    window: Duration,
+}
+
+impl Readiness {
+    /// Records a successful scrape, unless it reports that every one of its targets failed
+    ///
+    /// Targets are reported by families named `*_scrape_success`, such as one per cluster.
+    fn update(&self, metric_families: &[MetricFamily]) {
+        let mut success_metrics = metric_families
+            .iter()
+            .filter(|family| family.get_name().ends_with("_scrape_success"))
+            .flat_map(|family| family.get_metric())
+            .peekable();
+        let no_targets = success_metrics.peek().is_none();
+        if no_targets || success_metrics.any(|metric| metric.get_gauge().get_value() > 0.0) {
+            *self.last_ready.lock().unwrap() = Some(Instant::now());
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        self.last_ready
+            .lock()
+            .unwrap()
+            .is_some_and(|last_ready| last_ready.elapsed() <= self.window)
+    }
+}
+
+/// Tracks the scrapes currently running and counts those which completed
+struct InFlight {
+    concurrent: IntGauge,
+    in_progress: IntGauge,
+    cycles: IntCounter,
+}
+
+impl InFlight {
+    fn start(&self) -> InFlightGuard<'_> {
+        self.concurrent.inc();
+        self.in_progress.set(1);
+        InFlightGuard(self)
+    }
+}
+
+/// Marks the scrape as finished when dropped
+struct InFlightGuard<'a>(&'a InFlight);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.cycles.inc();
+        self.0.concurrent.dec();
+        if self.0.concurrent.get() == 0 {
+            self.0.in_progress.set(0);
+        }
+    }
+}
+
+/// Tracks when the credentials used for scraping expire
+struct CredentialsExpiry {
+    credentials_provider: SharedCredentialsProvider,
+    expiry: Gauge,
+}
+
+impl CredentialsExpiry {
+    async fn update(&self) {
+        match self.credentials_provider.provide_credentials().await {
+            Ok(credentials) => {
+                if let Some(expiry) = credentials.expiry() {
+                    let expiry = expiry.duration_since(UNIX_EPOCH).unwrap_or_default();
+                    self.expiry.set(expiry.as_secs_f64());
+                }
+            }
+            Err(err) => warn!("Failed to get the credentials of the assumed role: {}", err),
+        }
+    }
+}
+
+/// Runs the scrapes of both `/metrics` and `/refresh`, letting those requested while one runs
+/// share its result
+struct ScrapeRunner {
+    scraper: Arc<dyn Scraper>,
+    // Result of the most recent scrape, held while a scrape runs
+    last_result: tokio::sync::Mutex<Option<std::result::Result<(Instant, Registry), String>>>,
+    completed: AtomicUsize,
+}
+
+impl ScrapeRunner {
+    fn new(scraper: Arc<dyn Scraper>) -> Self {
+        Self {
+            scraper,
+            last_result: tokio::sync::Mutex::new(None),
+            completed: AtomicUsize::new(0),
+        }
+    }
+
+    /// Scrapes, or refreshes so that a cached result isn't served
+    ///
+    /// Returns the registry along with when it was actually scraped.
+    async fn run(&self, refresh: bool) -> Result<(Instant, Registry)> {
+        let completed = self.completed.load(Ordering::SeqCst);
+        let mut last_result = self.last_result.lock().await;
+        if self.completed.load(Ordering::SeqCst) != completed {
+            // A scrape ran while this one was waiting, so it's recent enough
+            if let Some(last_result) = last_result.as_ref() {
+                return last_result.clone().map_err(|err| eyre!(err));
+            }
+        }
+        let result = if refresh {
+            self.scraper.refresh().await
+        } else {
+            self.scraper.scrape().await
+        };
+        let result = match result {
+            Ok(registry) => Ok((self.scraper.scraped_at().await, registry)),
+            Err(err) => Err(err),
+        };
+        *last_result = Some(match &result {
+            Ok(scrape) => Ok(scrape.clone()),
+            Err(err) => Err(err.to_string()),
+        });
+        self.completed.fetch_add(1, Ordering::SeqCst);
+        result
+    }
 }
 
 impl Exporter {
+    /// Creates the exporter, whose own metrics are prefixed with its name
+    ///
+    /// `http_requests` historically had no prefix, and only gets one with `prefix_http_requests`.
     pub fn new(
-        socket_address: impl Into<SocketAddr>,
+        listen_addresses: Vec<ListenAddress>,
         tls_config: Option<TlsConfig>,
         scraper: Arc<dyn Scraper>,
         exporter_name: &str,
-        exporter_version: &str,
+        prefix_http_requests: bool,
+        build_info: &BuildInfo,
+        exporter_settings: &[(&str, f64)],
     ) -> Self {
+        let http_requests_name = if prefix_http_requests {
+            format!("{}_http_requests", exporter_name)
+        } else {
+            "http_requests".to_string()
+        };
         let exporter_opts = opts!(
-            "http_requests",
+            http_requests_name,
             "Number of HTTP requests received by the exporter"
         );
         let exporter_metrics = IntCounterVec::new(exporter_opts, &["status"])
@@ -40,78 +332,942 @@ impl Exporter {
             .expect("Failed to register exporter metrics family");
 
         let exporter_info = register_int_gauge_vec!(
-            opts!(format!("{}_info", exporter_name), "Exporter version"),
-            &["version"]
+            opts!(
+                format!("{}_info", exporter_name),
+                "Exporter version and build"
+            ),
+            &["version", "git_commit", "build_date", "rust_version"]
         )
         .expect("Failed to register exporter info");
         exporter_info
-            .get_metric_with_label_values(&[exporter_version])
+            .get_metric_with_label_values(&[
+                build_info.version,
+                build_info.git_commit,
+                build_info.build_date,
+                build_info.rust_version,
+            ])
             .expect("Failed to retrieve info metric")
             .set(1);
 
+        let exporter_config = register_gauge_vec!(
+            opts!(
+                format!("{}_config", exporter_name),
+                "Effective configuration the exporter is running with"
+            ),
+            &["setting"]
+        )
+        .expect("Failed to register exporter config");
+        for (setting, value) in exporter_settings {
+            exporter_config
+                .get_metric_with_label_values(&[setting])
+                .expect("Failed to retrieve config metric")
+                .set(*value);
+        }
+
+        let data_age = register_gauge!(opts!(
+            format!("{}_data_age_seconds", exporter_name),
+            "Time since the last successful scrape"
+        ))
+        .expect("Failed to register exporter data age");
+
+        let in_flight = InFlight {
+            concurrent: register_int_gauge!(opts!(
+                format!("{}_concurrent_scrapes", exporter_name),
+                "Number of scrapes currently running"
+            ))
+            .expect("Failed to register exporter concurrent scrapes"),
+            in_progress: register_int_gauge!(opts!(
+                format!("{}_scrape_in_progress", exporter_name),
+                "Whether a scrape is currently running"
+            ))
+            .expect("Failed to register exporter scrape in progress"),
+            cycles: register_int_counter!(opts!(
+                format!("{}_scrape_cycles_total", exporter_name),
+                "Number of completed scrapes, whether successful or not"
+            ))
+            .expect("Failed to register exporter scrape cycles"),
+        };
+
+        let startup_error = register_int_gauge!(opts!(
+            format!("{}_startup_error", exporter_name),
+            "Whether the exporter failed to set up its AWS clients"
+        ))
+        .expect("Failed to register exporter startup error");
+
         Self {
-            socket_address: socket_address.into(),
+            listen_addresses,
             tls_config,
             scraper,
             exporter_metrics: Arc::new(exporter_metrics),
+            data_age,
+            max_stale: None,
+            in_flight: Arc::new(in_flight),
+            credentials_expiry: None,
+            startup_error,
+            metrics_path: "metrics".to_string(),
+            ready_window: DEFAULT_READY_WINDOW,
+            authorization: None,
+            exporter_name: exporter_name.to_string(),
+            exporter_version: build_info.version.to_string(),
+        }
+    }
+
+    /// Refuses to serve metrics once the last successful scrape is older than `max_stale`
+    pub fn with_max_stale(mut self, max_stale: Option<Duration>) -> Self {
+        self.max_stale = max_stale;
+        self
+    }
+
+    /// Serves the metrics under the given path, e.g. `/v1/metrics`, instead of `/metrics`
+    pub fn with_metrics_path(mut self, metrics_path: &str) -> Self {
+        self.metrics_path = metrics_path.trim_start_matches('/').to_string();
+        self
+    }
+
+    /// Sets how recent the last successful scrape must be for `/ready` to succeed
+    pub fn with_ready_window(mut self, ready_window: Duration) -> Self {
+        self.ready_window = ready_window;
+        self
+    }
+
+    /// Requires basic auth to get the metrics, other endpoints stay open for probes
+    pub fn with_basic_auth(mut self, basic_auth: Option<BasicAuthConfig>) -> Self {
+        self.authorization = basic_auth.map(|basic_auth| {
+            format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", basic_auth.username, basic_auth.password))
+            )
+        });
+        self
+    }
+
+    /// Reports when the credentials of the assumed role expire, refreshing them if needed
+    pub fn with_credentials_expiry(
+        mut self,
+        credentials_provider: Option<SharedCredentialsProvider>,
+    ) -> Self {
+        self.credentials_expiry = credentials_provider.map(|credentials_provider| {
+            Arc::new(CredentialsExpiry {
+                credentials_provider,
+                expiry: register_gauge!(opts!(
+                    format!("{}_credentials_expiry_seconds", self.exporter_name),
+                    "Unix timestamp at which the credentials of the assumed role expire"
+                ))
+                .expect("Failed to register exporter credentials expiry"),
+            })
+        });
+        self
+    }
+
+    pub fn with_startup_error(self, startup_failed: bool) -> Self {
+        self.startup_error.set(startup_failed as i64);
+        self
+    }
+
+    /// Scrapes a single time and writes the exposition to stdout, without serving anything
+    pub async fn scrape_once(&self) -> Result<()> {
+        if let Some(credentials_expiry) = &self.credentials_expiry {
+            credentials_expiry.update().await;
         }
+        let registry = self.scraper.scrape().await?;
+        let mut metric_families = gather();
+        metric_families.extend(registry.gather());
+        TextEncoder::new()
+            .encode(&metric_families, &mut io::stdout().lock())
+            .wrap_err("Failed to write the metrics")
     }
 
-    pub async fn work(&self) {
-        let scraper = self.scraper.clone();
+    pub async fn work(&self) -> Result<()> {
+        let runner = Arc::new(ScrapeRunner::new(self.scraper.clone()));
+        let metrics_runner = runner.clone();
         let exporter_metrics = self.exporter_metrics.clone();
-        let metrics = warp::path("metrics")
-            .and_then(move || scrape(scraper.clone(), exporter_metrics.clone()));
+        let freshness = Arc::new(Freshness {
+            last_success: Mutex::new(Instant::now()),
+            max_stale: self.max_stale,
+            data_age: self.data_age.clone(),
+        });
+        let readiness = Arc::new(Readiness {
+            last_ready: Mutex::new(None),
+            window: self.ready_window,
+        });
+        let in_flight = self.in_flight.clone();
+        let credentials_expiry = self.credentials_expiry.clone();
+        let metrics_freshness = freshness.clone();
+        let metrics_readiness = readiness.clone();
+        let metrics_path = self
+            .metrics_path
+            .split('/')
+            .fold(warp::any().boxed(), |filter, segment| {
+                filter.and(warp::path(segment.to_string())).boxed()
+            });
+        let authorization = self.authorization.clone();
+        let authorized =
+            warp::header::optional::<String>("authorization").map(move |header: Option<String>| {
+                match &authorization {
+                    Some(expected) => header.is_some_and(|header| {
+                        constant_time_eq(header.as_bytes(), expected.as_bytes())
+                    }),
+                    None => true,
+                }
+            });
+        let metrics = metrics_path
+            .and(warp::path::end())
+            .and(authorized.clone())
+            .and(warp::header::optional::<String>("accept"))
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .and_then(
+                move |authorized, accept: Option<String>, accept_encoding: Option<String>| {
+                    scrape(
+                        authorized,
+                        ExpositionFormat::negotiate(accept.as_deref()),
+                        accepts_gzip(accept_encoding.as_deref()),
+                        metrics_runner.clone(),
+                        exporter_metrics.clone(),
+                        metrics_freshness.clone(),
+                        metrics_readiness.clone(),
+                        in_flight.clone(),
+                        credentials_expiry.clone(),
+                    )
+                },
+            );
+
+        let refresh = warp::path("refresh")
+            .and(warp::post())
+            .and(authorized)
+            .and_then(move |authorized| refresh(authorized, runner.clone(), freshness.clone()));
+
+        // A standby is ready, so that rollouts don't wait for it to become the leader
+        let ready_scraper = self.scraper.clone();
+        let ready = warp::path("ready").map(move || {
+            let status = if readiness.is_ready() || ready_scraper.is_standby() {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            warp::reply::with_status(warp::reply(), status)
+        });
+
+        let landing_page = format!(
+            "<html>\n\
+             <head><title>{name}</title></head>\n\
+             <body>\n\
+             <h1>{name} {version}</h1>\n\
+             <p><a href=\"/{metrics_path}\">Metrics</a></p>\n\
+             <p><a href=\"/status\">Status</a></p>\n\
+             </body>\n\
+             </html>\n",
+            name = self.exporter_name,
+            version = self.exporter_version,
+            metrics_path = self.metrics_path,
+        );
+        let index = warp::path::end().map(move || warp::reply::html(landing_page.clone()));
 
         let status = warp::path("status").map(warp::reply::reply);
-        let route = status.or(metrics);
+        let route = index
+            .or(status)
+            .or(ready)
+            .or(refresh)
+            .or(metrics)
+            .map(Reply::into_response);
+        // Rejected requests are answered here rather than by warp, so that they're logged too
+        let route = warp::any()
+            .map(Instant::now)
+            .and(warp::method())
+            .and(warp::path::full())
+            .and(route.recover(rejected).unify())
+            .map(log_request)
+            .boxed();
 
+        // Every server stops on the same signal
+        let shutdown = shutdown_signal().boxed().shared();
+        future::try_join_all(
+            self.listen_addresses
+                .iter()
+                .map(|listen_address| self.serve(route.clone(), listen_address, shutdown.clone())),
+        )
+        .await?;
+        info!("Shut down");
+        Ok(())
+    }
+
+    /// Serves the routes on the address until the shutdown signal is received
+    async fn serve(
+        &self,
+        route: BoxedFilter<(warp::reply::Response,)>,
+        listen_address: &ListenAddress,
+        shutdown: Shared<BoxFuture<'static, ()>>,
+    ) -> Result<()> {
         let server = warp::serve(route);
-        match &self.tls_config {
-            Some(tls_config) => {
-                let server = server
+        match (listen_address, &self.tls_config) {
+            (ListenAddress::Tcp(socket_address), Some(tls_config)) => {
+                let (_, server) = server
                     .tls()
                     .key_path(&tls_config.key)
-                    .cert_path(&tls_config.cert);
-                server.bind(self.socket_address).await;
+                    .cert_path(&tls_config.cert)
+                    .bind_with_graceful_shutdown(*socket_address, shutdown);
+                server.await;
+            }
+            (ListenAddress::Tcp(socket_address), None) => {
+                let (_, server) = server
+                    .try_bind_with_graceful_shutdown(*socket_address, shutdown)
+                    .wrap_err_with(|| format!("Failed to listen on {}", socket_address))?;
+                server.await;
+            }
+            #[cfg(unix)]
+            (ListenAddress::Unix(_), Some(_)) => {
+                return Err(eyre!("TLS isn't supported when listening on a Unix socket"));
+            }
+            #[cfg(unix)]
+            (ListenAddress::Unix(path), None) => {
+                let listener = tokio::net::UnixListener::bind(path)
+                    .wrap_err_with(|| format!("Failed to listen on {}", listen_address))?;
+                let incoming = tokio_stream::wrappers::UnixListenerStream::new(listener);
+                server
+                    .serve_incoming_with_graceful_shutdown(incoming, shutdown)
+                    .await;
+                if let Err(err) = std::fs::remove_file(path) {
+                    warn!("Failed to remove the socket {}: {}", path.display(), err);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Resolves on SIGTERM or Ctrl-C, after which the server stops accepting connections
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                warn!("Failed to listen for SIGTERM: {}", err);
+                futures::future::pending::<()>().await;
             }
-            None => server.try_bind(self.socket_address).await,
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = futures::future::pending::<()>();
+
+    let interrupt = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl-C: {}", err);
+            futures::future::pending::<()>().await;
+        }
+    };
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
     }
+    info!("Received shutdown signal, waiting for in-flight requests to finish");
+}
+
+/// Checks that the address can be listened on, by binding it and immediately releasing it
+///
+/// A Unix socket left behind by a previous run is removed, unless something still listens on it.
+pub fn check_bindable(listen_address: &ListenAddress) -> Result<()> {
+    let socket_address = match listen_address {
+        ListenAddress::Tcp(socket_address) => socket_address,
+        #[cfg(unix)]
+        ListenAddress::Unix(path) => {
+            if !path.exists() {
+                return Ok(());
+            }
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(eyre!(
+                    "Cannot listen on {}, as it's already in use. Set another address with `--listen`",
+                    listen_address
+                ));
+            }
+            return std::fs::remove_file(path)
+                .wrap_err_with(|| format!("Cannot remove the stale socket {}", listen_address));
+        }
+    };
+    match TcpListener::bind(socket_address) {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse => Err(eyre!(
+            "Cannot listen on {}, as it's already in use. Set another address with `--listen`",
+            socket_address
+        )),
+        Err(err) => Err(err).wrap_err_with(|| format!("Cannot listen on {}", socket_address)),
+    }
+}
+
+/// Logs the request once its response is ready, along with the size of its body if it's known
+///
+/// Streamed bodies, such as uncompressed metrics, are still being sent, so they have no size and
+/// only their headers count towards the duration.
+fn log_request(
+    start: Instant,
+    method: Method,
+    path: FullPath,
+    response: warp::reply::Response,
+) -> warp::reply::Response {
+    let size = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|content_length| content_length.to_str().ok()?.parse::<u64>().ok())
+        .or_else(|| response.body().size_hint().exact());
+    info!(
+        method = method.as_str(),
+        path = path.as_str(),
+        status = response.status().as_u16(),
+        size,
+        duration = start.elapsed().as_secs_f64(),
+        "Served HTTP request"
+    );
+    response
+}
+
+/// Answers a request which no route accepted with the status warp would have given it
+async fn rejected(rejection: Rejection) -> std::result::Result<warp::reply::Response, Infallible> {
+    let status = if rejection.is_not_found() {
+        StatusCode::NOT_FOUND
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        StatusCode::METHOD_NOT_ALLOWED
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+    Ok(warp::reply::with_status(warp::reply(), status).into_response())
+}
+
+/// Triggers a scrape and only replies once it's done
+async fn refresh(
+    authorized: bool,
+    runner: Arc<ScrapeRunner>,
+    freshness: Arc<Freshness>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    if !authorized {
+        return Ok(unauthorized());
+    }
+    let scraped_at = match runner.run(true).await {
+        Ok((scraped_at, _)) => Some(scraped_at),
+        Err(err) => {
+            warn!("On-demand refresh failed: {}", err);
+            None
+        }
+    };
+    freshness.update(scraped_at);
+    let status = if scraped_at.is_some() {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::BAD_GATEWAY
+    };
+    Ok(warp::reply::with_status(warp::reply(), status).into_response())
 }
 
 // Separate function helps with async lifetime requirements
+#[allow(clippy::too_many_arguments)]
 async fn scrape(
-    scraper: Arc<dyn Scraper>,
+    authorized: bool,
+    format: ExpositionFormat,
+    gzip: bool,
+    runner: Arc<ScrapeRunner>,
     exporter_metrics_family: Arc<IntCounterVec>,
+    freshness: Arc<Freshness>,
+    readiness: Arc<Readiness>,
+    in_flight: Arc<InFlight>,
+    credentials_expiry: Option<Arc<CredentialsExpiry>>,
 ) -> std::result::Result<impl Reply, Infallible> {
+    if !authorized {
+        exporter_metrics_family
+            .with_label_values(&["unauthorized"])
+            .inc();
+        return Ok(unauthorized());
+    }
+
     // The match sets the label to increment for the http metric, either success or error
     // Status gauge represents the status of only this particular scrape
     let labels: &[&str];
 
+    let in_flight_guard = in_flight.start();
+    if let Some(credentials_expiry) = &credentials_expiry {
+        credentials_expiry.update().await;
+    }
     // This registry contains the metrics for this particular scrape
-    let registry = match scraper.scrape().await {
-        Ok(registry) => {
+    let scrape = match runner.run(false).await {
+        Ok(scrape) => {
             labels = &["success"];
-            registry
+            Some(scrape)
+        }
+        Err(_) if runner.scraper.is_standby() => {
+            exporter_metrics_family
+                .with_label_values(&["standby"])
+                .inc();
+            return Ok(Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from(
+                    "Standby replica, the leader serves the metrics\n",
+                ))
+                .unwrap());
         }
         Err(err) => {
             warn!("{}", err);
             labels = &["error"];
-            Registry::new()
+            None
         }
     };
 
-    exporter_metrics_family
-        .get_metric_with_label_values(labels)
-        .unwrap()
-        .inc();
+    exporter_metrics_family.with_label_values(labels).inc();
+    drop(in_flight_guard);
+
+    let age = freshness.update(scrape.as_ref().map(|(scraped_at, _)| *scraped_at));
+    if freshness.is_too_stale(age) {
+        warn!(
+            "Refusing to serve metrics, last successful scrape was {:?} ago",
+            age
+        );
+        return Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::from("Metrics are too stale\n"))
+            .unwrap());
+    }
+    let scrape_families = scrape.map(|(_, registry)| registry.gather());
+    if let Some(scrape_families) = &scrape_families {
+        readiness.update(scrape_families);
+    }
 
-    let mut buffer = vec![];
-    let encoder = TextEncoder::new();
+    let content_type = format.content_type().to_string();
 
     let mut metric_families = gather(); // Gather the common metrics family
-    metric_families.extend(registry.gather()); // Add the metrics from this particular scrape
-    encoder.encode(&metric_families, &mut buffer).unwrap();
-    Ok(String::from_utf8(buffer).unwrap())
+                                        // Add the metrics from this particular scrape
+    metric_families.extend(scrape_families.into_iter().flatten());
+
+    // Compressing needs the whole exposition anyway, so it's encoded before replying
+    if gzip {
+        let mut body = GzEncoder::new(vec![], Compression::default());
+        let body = match format
+            .encode(&metric_families, &mut body)
+            .map_err(|err| err.to_string())
+            .and_then(|()| {
+                body.write_all(format.trailer())
+                    .map_err(|err| err.to_string())
+            })
+            .and_then(|()| body.finish().map_err(|err| err.to_string()))
+        {
+            Ok(body) => body,
+            Err(err) => {
+                warn!("Failed to encode compressed metrics: {}", err);
+                exporter_metrics_family.with_label_values(&["error"]).inc();
+                return Ok(internal_error());
+            }
+        };
+        let response = Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Body::from(body))
+            .unwrap_or_else(|err| {
+                warn!("Failed to build the metrics response: {}", err);
+                internal_error()
+            });
+        return Ok(response);
+    }
+
+    // Encode one family at a time as the body is sent, so the whole exposition is never buffered.
+    // The status is already sent by then, so failures can only be logged and abort the body.
+    let body = stream::iter(metric_families).map(move |metric_family| {
+        let name = metric_family.get_name().to_string();
+        let mut buffer = vec![];
+        format
+            .encode(&[metric_family], &mut buffer)
+            .map(|()| buffer)
+            .map_err(|err| {
+                warn!("Failed to encode metric family `{}`: {}", name, err);
+                exporter_metrics_family.with_label_values(&["error"]).inc();
+                io::Error::other(err.to_string())
+            })
+    });
+    let body = body.chain(stream::iter(
+        Some(format.trailer())
+            .filter(|trailer| !trailer.is_empty())
+            .map(|trailer| Ok(trailer.to_vec())),
+    ));
+    let response = Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::wrap_stream(body))
+        .unwrap_or_else(|err| {
+            warn!("Failed to build the metrics response: {}", err);
+            internal_error()
+        });
+    Ok(response)
+}
+
+/// Format of the metrics sent to a client, depending on what it accepts
+#[derive(Clone, Copy)]
+enum ExpositionFormat {
+    Prometheus,
+    OpenMetrics,
+}
+
+impl ExpositionFormat {
+    fn negotiate(accept: Option<&str>) -> Self {
+        match accept {
+            Some(accept) if accept.contains("application/openmetrics-text") => Self::OpenMetrics,
+            _ => Self::Prometheus,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Prometheus => TEXT_FORMAT,
+            Self::OpenMetrics => OPENMETRICS_FORMAT,
+        }
+    }
+
+    /// Encodes the families, which may only be part of the exposition
+    fn encode<W: Write>(
+        self,
+        metric_families: &[MetricFamily],
+        writer: &mut W,
+    ) -> prometheus::Result<()> {
+        match self {
+            Self::Prometheus => TextEncoder::new().encode(metric_families, writer),
+            Self::OpenMetrics => OpenMetricsEncoder.encode_families(metric_families, writer),
+        }
+    }
+
+    /// Ends the exposition
+    fn trailer(self) -> &'static [u8] {
+        match self {
+            Self::Prometheus => b"",
+            Self::OpenMetrics => openmetrics::EOF,
+        }
+    }
+}
+
+/// Whether the `Accept-Encoding` header allows gzip, ignoring preferences other than refusal
+fn accepts_gzip(accept_encoding: Option<&str>) -> bool {
+    accept_encoding
+        .into_iter()
+        .flat_map(|header| header.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let refused = parts.any(|parameter| {
+                parameter
+                    .strip_prefix("q=")
+                    .and_then(|quality| quality.parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            name.eq_ignore_ascii_case("gzip") && !refused
+        })
+}
+
+/// Compares in a time which only depends on the lengths, to not leak how much of a secret matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn unauthorized() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(WWW_AUTHENTICATE, "Basic realm=\"metrics\"")
+        .body(Body::from("Unauthorized\n"))
+        .unwrap()
+}
+
+fn internal_error() -> Response<Body> {
+    let mut response = Response::new(Body::from("Failed to encode metrics\n"));
+    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    response
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    /// Returns a canned registry, or fails on demand
+    pub(crate) struct MockScraper {
+        registry: Registry,
+        fail: AtomicBool,
+        standby: AtomicBool,
+        calls: AtomicUsize,
+        delay: Duration,
+    }
+
+    impl MockScraper {
+        pub(crate) fn new() -> Self {
+            let registry = Registry::new();
+            let gauge =
+                IntGauge::new("mock_scraped", "Metric returned by the mock scraper").unwrap();
+            gauge.set(42);
+            registry.register(Box::new(gauge)).unwrap();
+            Self {
+                registry,
+                fail: AtomicBool::new(false),
+                standby: AtomicBool::new(false),
+                calls: AtomicUsize::new(0),
+                delay: Duration::ZERO,
+            }
+        }
+
+        pub(crate) fn slow(delay: Duration) -> Self {
+            Self {
+                delay,
+                ..Self::new()
+            }
+        }
+
+        fn failing() -> Self {
+            let scraper = Self::new();
+            scraper.fail.store(true, Ordering::SeqCst);
+            scraper
+        }
+    }
+
+    #[async_trait]
+    impl Scraper for MockScraper {
+        async fn scrape(&self) -> Result<Registry> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            if self.fail.load(Ordering::SeqCst) {
+                Err(eyre!("Mock scrape failure"))
+            } else {
+                Ok(self.registry.clone())
+            }
+        }
+
+        fn is_standby(&self) -> bool {
+            self.standby.load(Ordering::SeqCst)
+        }
+    }
+
+    /// State shared by the handler calls of a test, with metrics registered nowhere
+    struct Handler {
+        scraper: Arc<MockScraper>,
+        runner: Arc<ScrapeRunner>,
+        http_requests: Arc<IntCounterVec>,
+        freshness: Arc<Freshness>,
+        readiness: Arc<Readiness>,
+        in_flight: Arc<InFlight>,
+    }
+
+    impl Handler {
+        fn new(scraper: MockScraper) -> Self {
+            let scraper = Arc::new(scraper);
+            Self {
+                runner: Arc::new(ScrapeRunner::new(scraper.clone())),
+                scraper,
+                http_requests: Arc::new(
+                    IntCounterVec::new(opts!("http_requests", "HTTP requests"), &["status"])
+                        .unwrap(),
+                ),
+                freshness: Arc::new(Freshness {
+                    last_success: Mutex::new(Instant::now()),
+                    max_stale: None,
+                    data_age: Gauge::new("data_age_seconds", "Data age").unwrap(),
+                }),
+                readiness: Arc::new(Readiness {
+                    last_ready: Mutex::new(None),
+                    window: DEFAULT_READY_WINDOW,
+                }),
+                in_flight: Arc::new(InFlight {
+                    concurrent: IntGauge::new("concurrent_scrapes", "Concurrent scrapes").unwrap(),
+                    in_progress: IntGauge::new("scrape_in_progress", "Scrape in progress").unwrap(),
+                    cycles: IntCounter::new("scrape_cycles_total", "Scrape cycles").unwrap(),
+                }),
+            }
+        }
+
+        fn with_max_stale(mut self, max_stale: Duration) -> Self {
+            self.freshness = Arc::new(Freshness {
+                last_success: Mutex::new(Instant::now() - max_stale * 2),
+                max_stale: Some(max_stale),
+                data_age: self.freshness.data_age.clone(),
+            });
+            self
+        }
+
+        /// Calls the metrics handler, returning the status and body of its response
+        async fn get(&self, authorized: bool, format: ExpositionFormat) -> (StatusCode, String) {
+            let reply = scrape(
+                authorized,
+                format,
+                false,
+                self.runner.clone(),
+                self.http_requests.clone(),
+                self.freshness.clone(),
+                self.readiness.clone(),
+                self.in_flight.clone(),
+                None,
+            )
+            .await
+            .unwrap();
+            let response = reply.into_response();
+            let status = response.status();
+            let body = warp::hyper::body::to_bytes(response.into_body())
+                .await
+                .unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        }
+
+        /// Calls the refresh handler, returning the status of its response
+        async fn refresh(&self, authorized: bool) -> StatusCode {
+            refresh(authorized, self.runner.clone(), self.freshness.clone())
+                .await
+                .unwrap()
+                .status()
+        }
+
+        fn requests(&self, status: &str) -> u64 {
+            self.http_requests.with_label_values(&[status]).get()
+        }
+    }
+
+    #[tokio::test]
+    async fn successful_scrape_is_served_and_counted() {
+        let handler = Handler::new(MockScraper::new());
+
+        let (status, body) = handler.get(true, ExpositionFormat::Prometheus).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("# TYPE mock_scraped gauge"));
+        assert!(body.contains("mock_scraped 42"));
+        assert_eq!(handler.requests("success"), 1);
+        assert_eq!(handler.requests("error"), 0);
+        assert_eq!(handler.in_flight.cycles.get(), 1);
+        assert_eq!(handler.in_flight.in_progress.get(), 0);
+        assert!(handler.readiness.is_ready());
+    }
+
+    #[tokio::test]
+    async fn failed_scrape_is_counted_as_error() {
+        let handler = Handler::new(MockScraper::failing());
+
+        let (status, body) = handler.get(true, ExpositionFormat::Prometheus).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(!body.contains("mock_scraped"));
+        assert_eq!(handler.requests("success"), 0);
+        assert_eq!(handler.requests("error"), 1);
+        assert!(!handler.readiness.is_ready());
+    }
+
+    #[tokio::test]
+    async fn standby_answers_unavailable() {
+        let handler = Handler::new(MockScraper::failing());
+        handler.scraper.standby.store(true, Ordering::SeqCst);
+
+        let (status, body) = handler.get(true, ExpositionFormat::Prometheus).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!body.contains("mock_scraped"));
+        assert_eq!(handler.requests("standby"), 1);
+        assert_eq!(handler.requests("error"), 0);
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_are_answered_not_found() {
+        let response = rejected(warp::reject::not_found()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn requests_are_counted_separately() {
+        let handler = Handler::new(MockScraper::new());
+        handler.get(true, ExpositionFormat::Prometheus).await;
+        handler.scraper.fail.store(true, Ordering::SeqCst);
+        handler.get(true, ExpositionFormat::Prometheus).await;
+        handler.get(true, ExpositionFormat::Prometheus).await;
+
+        assert_eq!(handler.requests("success"), 1);
+        assert_eq!(handler.requests("error"), 2);
+        assert_eq!(handler.scraper.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn unauthorized_request_does_not_scrape() {
+        let handler = Handler::new(MockScraper::new());
+
+        let (status, _) = handler.get(false, ExpositionFormat::Prometheus).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(handler.requests("unauthorized"), 1);
+        assert_eq!(handler.scraper.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn unauthorized_refresh_does_not_scrape() {
+        let handler = Handler::new(MockScraper::new());
+
+        assert_eq!(handler.refresh(false).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(handler.scraper.calls.load(Ordering::SeqCst), 0);
+
+        assert_eq!(handler.refresh(true).await, StatusCode::NO_CONTENT);
+        assert_eq!(handler.scraper.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn refresh_bypasses_the_cache() {
+        let scraper = Arc::new(MockScraper::new());
+        let caching_scraper = Arc::new(CachingScraper::new(
+            scraper.clone(),
+            Duration::from_secs(3600),
+        ));
+        let runner = ScrapeRunner::new(caching_scraper);
+
+        runner.run(false).await.unwrap();
+        runner.run(false).await.unwrap();
+        assert_eq!(scraper.calls.load(Ordering::SeqCst), 1);
+
+        runner.run(true).await.unwrap();
+        assert_eq!(scraper.calls.load(Ordering::SeqCst), 2);
+        // The refreshed registry is cached in turn
+        runner.run(false).await.unwrap();
+        assert_eq!(scraper.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn cached_registries_keep_the_time_they_were_scraped() {
+        let caching_scraper = Arc::new(CachingScraper::new(
+            Arc::new(MockScraper::new()),
+            Duration::from_secs(3600),
+        ));
+        let runner = ScrapeRunner::new(caching_scraper);
+
+        let (scraped_at, _) = runner.run(false).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let (cached_at, _) = runner.run(false).await.unwrap();
+        assert_eq!(cached_at, scraped_at);
+    }
+
+    #[tokio::test]
+    async fn metrics_and_refresh_share_the_scrape_in_progress() {
+        let handler = Handler::new(MockScraper::slow(Duration::from_millis(100)));
+
+        let (first, refreshed, second) = tokio::join!(
+            handler.get(true, ExpositionFormat::Prometheus),
+            handler.refresh(true),
+            handler.get(true, ExpositionFormat::Prometheus),
+        );
+
+        assert!(first.1.contains("mock_scraped 42"));
+        assert_eq!(refreshed, StatusCode::NO_CONTENT);
+        assert!(second.1.contains("mock_scraped 42"));
+        assert_eq!(handler.scraper.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn openmetrics_exposition_is_terminated() {
+        let handler = Handler::new(MockScraper::new());
+
+        let (_, body) = handler.get(true, ExpositionFormat::OpenMetrics).await;
+
+        assert!(body.contains("mock_scraped 42"));
+        assert!(body.ends_with(std::str::from_utf8(openmetrics::EOF).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn stale_metrics_are_refused() {
+        let handler = Handler::new(MockScraper::failing()).with_max_stale(Duration::from_secs(60));
+
+        let (status, _) = handler.get(true, ExpositionFormat::Prometheus).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(handler.requests("error"), 1);
+    }
 }
@@ -0,0 +1,145 @@
+use crate::exporter::Scraper;
+use async_trait::async_trait;
+use aws_sdk_dynamodb::model::AttributeValue;
+use aws_sdk_dynamodb::SdkError;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use prometheus::Registry;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// A lease shared between exporter replicas, so only one of them queries AWS at a time
+#[async_trait]
+pub trait LeaderLock: Send + Sync {
+    /// Acquires the lease, or renews it if it's already held
+    ///
+    /// Returns `false` if another replica holds the lease.
+    async fn try_acquire(&self) -> Result<bool>;
+}
+
+/// Lease stored as a single item in a DynamoDB table whose partition key is `lock_name`
+pub struct DynamoDbLock {
+    client: aws_sdk_dynamodb::Client,
+    table_name: String,
+    lock_name: String,
+    owner: String,
+    lease: Duration,
+}
+
+impl DynamoDbLock {
+    pub fn new(
+        client: aws_sdk_dynamodb::Client,
+        table_name: &str,
+        lock_name: &str,
+        owner: &str,
+        lease: Duration,
+    ) -> Self {
+        Self {
+            client,
+            table_name: table_name.to_string(),
+            lock_name: lock_name.to_string(),
+            owner: owner.to_string(),
+            lease,
+        }
+    }
+}
+
+#[async_trait]
+impl LeaderLock for DynamoDbLock {
+    async fn try_acquire(&self) -> Result<bool> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let response = self
+            .client
+            .put_item()
+            .table_name(&self.table_name)
+            .item("lock_name", AttributeValue::S(self.lock_name.clone()))
+            .item("owner", AttributeValue::S(self.owner.clone()))
+            .item(
+                "expires_at",
+                AttributeValue::N((now + self.lease.as_secs()).to_string()),
+            )
+            .condition_expression(
+                "attribute_not_exists(lock_name) OR expires_at < :now OR #owner = :owner",
+            )
+            .expression_attribute_names("#owner", "owner")
+            .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+            .expression_attribute_values(":owner", AttributeValue::S(self.owner.clone()))
+            .send()
+            .await;
+        match response {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError { err, .. })
+                if err.is_conditional_check_failed_exception() =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Only scrapes when this replica holds the lease
+///
+/// The lease is renewed in the background, so that it doesn't expire when Prometheus scrapes less
+/// often than the lease lasts. A standby replica answers `/metrics` with 503, rather than what it
+/// scraped while it was the leader, which would duplicate the leader's series with stale values.
+/// It's still reported as ready, so that rollouts don't wait for it to become the leader.
+pub struct LeaderScraper {
+    scraper: Arc<dyn Scraper>,
+    // Whether this replica holds the lease, `None` until the first attempt to acquire it
+    is_leader: watch::Receiver<Option<bool>>,
+}
+
+impl LeaderScraper {
+    /// Wraps the scraper and starts renewing the lease in the background, a few times per lease
+    pub fn spawn(scraper: Arc<dyn Scraper>, lock: Arc<dyn LeaderLock>, lease: Duration) -> Self {
+        let (sender, is_leader) = watch::channel(None);
+        tokio::spawn(renew(lock, lease / 3, sender));
+        Self { scraper, is_leader }
+    }
+}
+
+/// Tries to acquire or renew the lease every interval, as long as the scraper is around
+///
+/// The lease is considered lost when it can't be renewed, so that two replicas never both scrape.
+async fn renew(lock: Arc<dyn LeaderLock>, interval: Duration, sender: watch::Sender<Option<bool>>) {
+    loop {
+        let is_leader = match lock.try_acquire().await {
+            Ok(is_leader) => is_leader,
+            Err(err) => {
+                warn!("Failed to renew the lease: {}", err);
+                false
+            }
+        };
+        if *sender.borrow() != Some(is_leader) {
+            info!(is_leader, "Leadership changed");
+        }
+        if sender.send(Some(is_leader)).is_err() {
+            return;
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[async_trait]
+impl Scraper for LeaderScraper {
+    async fn scrape(&self) -> Result<Registry> {
+        let mut is_leader = self.is_leader.clone();
+        while is_leader.borrow().is_none() {
+            is_leader.changed().await?;
+        }
+        let is_leader = is_leader.borrow().unwrap_or_default();
+        if is_leader {
+            self.scraper.scrape().await
+        } else {
+            info!("Skipping scrape, another replica is the leader");
+            Err(eyre!("Not the leader"))
+        }
+    }
+
+    fn is_standby(&self) -> bool {
+        *self.is_leader.borrow() == Some(false)
+    }
+}
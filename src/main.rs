@@ -1,55 +1,356 @@
 mod aws;
+mod cloudwatch;
 mod config;
 mod exporter;
+mod ha;
+mod openmetrics;
+mod remote_write;
+#[cfg(feature = "bench")]
+mod sample;
 
-use crate::aws::{get_credentials_provider, EcsClient};
-use crate::exporter::Exporter;
+use crate::aws::{
+    get_credentials_provider, get_provider_without_imds, get_web_identity_provider, EcsClient,
+    DEFAULT_METRICS_PREFIX,
+};
+use crate::cloudwatch::CloudWatchScraper;
+use crate::config::LogFormat;
+use crate::exporter::{
+    check_bindable, BuildInfo, CachingScraper, Exporter, FailingScraper, Scraper,
+};
+use crate::ha::{DynamoDbLock, LeaderScraper};
+use crate::remote_write::RemoteWriteScraper;
+use aws_config::environment::EnvironmentVariableRegionProvider;
 use aws_config::meta::region::RegionProviderChain;
+use aws_config::profile::ProfileFileRegionProvider;
+use aws_smithy_types::timeout::TimeoutConfig;
 use aws_types::credentials::SharedCredentialsProvider;
+use aws_types::region::Region;
+use color_eyre::eyre::{eyre, WrapErr};
 use color_eyre::Result;
 use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .json()
-        .init();
+    let config = config::Config::from_args();
+
+    let subscriber = tracing_subscriber::fmt().with_env_filter(EnvFilter::new(&config.log_level));
+    match config.log_format {
+        LogFormat::Json => subscriber.json().init(),
+        LogFormat::Text => subscriber.init(),
+    }
     color_eyre::install()?;
+    for warning in &config.warnings {
+        warn!("{}", warning);
+    }
 
-    let config = config::Config::from_args();
+    // Checked before setting up AWS clients, as the TLS server panics if it can't bind, and
+    // warp's bind errors don't tell why it failed
+    if !config.once {
+        for listen_address in &config.listen_addresses {
+            check_bindable(listen_address)?;
+        }
+    }
 
-    let region = RegionProviderChain::first_try(config.region)
-        .or_default_provider()
-        .region()
-        .await
-        .expect("Failed to determine region");
+    #[cfg(feature = "bench")]
+    let built = match config.sample {
+        Some(sample_config) => {
+            let scraper: Arc<dyn Scraper> = Arc::new(sample::HttpLoadScraper::new(sample_config));
+            Ok((scraper, vec![], None))
+        }
+        None => build_scraper(&config).await,
+    };
+    #[cfg(not(feature = "bench"))]
+    let built = build_scraper(&config).await;
 
-    let default_credentials_provider =
-        aws_config::default_provider::credentials::default_provider().await;
+    let (scraper, exporter_settings, role_credentials, startup_failed) = match built {
+        Ok((scraper, exporter_settings, role_credentials)) => {
+            (scraper, exporter_settings, role_credentials, false)
+        }
+        Err(err) if config.fail_fast => return Err(err),
+        Err(err) => {
+            error!("Failed to start, serving errors only: {:?}", err);
+            let scraper: Arc<dyn Scraper> = Arc::new(FailingScraper::new(err.to_string()));
+            (scraper, vec![], None, true)
+        }
+    };
 
-    let mut aws_config_loader = aws_config::from_env().region(region.clone());
+    let exporter = Exporter::new(
+        config.listen_addresses,
+        config.tls,
+        scraper,
+        &config.exporter_name,
+        config.metrics_prefix.is_some(),
+        &BuildInfo {
+            version: &config.app_version,
+            git_commit: option_env!("GIT_COMMIT").unwrap_or("unknown"),
+            build_date: option_env!("BUILD_DATE").unwrap_or("unknown"),
+            rust_version: option_env!("RUST_VERSION").unwrap_or("unknown"),
+        },
+        &exporter_settings,
+    )
+    .with_max_stale(config.max_stale)
+    .with_metrics_path(&config.metrics_path)
+    .with_basic_auth(config.basic_auth)
+    .with_ready_window(config.ready_window)
+    .with_credentials_expiry(role_credentials)
+    .with_startup_error(startup_failed);
+    if config.once {
+        return exporter.scrape_once().await;
+    }
+    exporter.work().await
+}
+
+/// Sets up the AWS clients and returns the scraper along with the settings it ended up using
+/// and the credentials of the assumed role, if any
+async fn build_scraper(
+    config: &config::Config,
+) -> Result<(
+    Arc<dyn Scraper>,
+    Vec<(&'static str, f64)>,
+    Option<SharedCredentialsProvider>,
+)> {
+    let region_provider = RegionProviderChain::first_try(config.region.clone());
+    let region_provider = if config.no_imds {
+        region_provider
+            .or_else(EnvironmentVariableRegionProvider::new())
+            .or_else(ProfileFileRegionProvider::builder().build())
+    } else {
+        region_provider.or_default_provider()
+    };
+    let region = region_provider.region().await.ok_or_else(|| {
+        eyre!("Failed to determine the AWS region, set it with `--region` or `AWS_REGION`")
+    })?;
 
-    if let Some(role) = config.aws_role {
-        let default_credentials_provider =
-            SharedCredentialsProvider::new(default_credentials_provider);
-        let cp = get_credentials_provider(default_credentials_provider, &role, None, None, region);
-        aws_config_loader = aws_config_loader.credentials_provider(cp);
+    // Explicit credentials skip the default chain entirely, which would otherwise probe IMDS
+    let explicit_credentials_provider = if let Some(credentials) = &config.static_credentials {
+        Some(SharedCredentialsProvider::new(credentials.clone()))
+    } else {
+        config.web_identity.as_ref().map(|web_identity| {
+            get_web_identity_provider(
+                &web_identity.token_file,
+                &web_identity.role,
+                config.session_name.as_deref(),
+                region.clone(),
+            )
+        })
+    };
+
+    let timeout_config = TimeoutConfig::new()
+        .with_connect_timeout(Some(config.aws_connect_timeout))
+        .with_api_call_timeout(Some(config.aws_operation_timeout));
+
+    let mut aws_config_loader = aws_config::from_env()
+        .region(region.clone())
+        .timeout_config(timeout_config.clone());
+    let base_credentials_provider = match explicit_credentials_provider {
+        Some(credentials_provider) => {
+            aws_config_loader =
+                aws_config_loader.credentials_provider(credentials_provider.clone());
+            credentials_provider
+        }
+        None if config.no_imds => {
+            let credentials_provider = get_provider_without_imds(region.clone());
+            aws_config_loader =
+                aws_config_loader.credentials_provider(credentials_provider.clone());
+            credentials_provider
+        }
+        None => SharedCredentialsProvider::new(
+            aws_config::default_provider::credentials::default_provider().await,
+        ),
+    };
+
+    let mut role_credentials = None;
+    if let Some(role) = &config.aws_role {
+        let cp = SharedCredentialsProvider::new(get_credentials_provider(
+            base_credentials_provider.clone(),
+            role,
+            config.external_id.as_deref(),
+            config.session_name.as_deref(),
+            region.clone(),
+            config.sts_endpoint.as_deref(),
+        ));
+        aws_config_loader = aws_config_loader.credentials_provider(cp.clone());
+        role_credentials = Some(cp);
     };
 
     let aws_config = aws_config_loader.load().await;
 
+    if config.sts_check {
+        let identity = aws_sdk_sts::Client::new(&aws_config)
+            .get_caller_identity()
+            .send()
+            .await
+            .wrap_err("Failed to assume the configured role")?;
+        info!(
+            arn = identity.arn.as_deref(),
+            "Successfully assumed the configured role"
+        );
+    }
+
+    let mut exporter_settings = vec![];
+    if let Some(retry_config) = aws_config.retry_config() {
+        exporter_settings.push(("max_attempts", retry_config.max_attempts() as f64));
+    }
+    exporter_settings.push(("max_concurrency", config.max_concurrency as f64));
+    exporter_settings.push(("max_retries", config.max_retries as f64));
+    exporter_settings.push(("cluster_parallelism", config.cluster_parallelism as f64));
+    exporter_settings.push(("scrape_timeout", config.scrape_timeout.as_secs_f64()));
+    exporter_settings.push((
+        "aws_connect_timeout",
+        config.aws_connect_timeout.as_secs_f64(),
+    ));
+    exporter_settings.push((
+        "aws_operation_timeout",
+        config.aws_operation_timeout.as_secs_f64(),
+    ));
+    exporter_settings.push((
+        "cache_ttl",
+        config.cache_ttl.unwrap_or_default().as_secs_f64(),
+    ));
+    if let Some(shared_concurrency) = config.shared_concurrency {
+        exporter_settings.push(("shared_concurrency", shared_concurrency as f64));
+    }
+    if let Some(describe_chunk_size) = config.describe_chunk_size {
+        exporter_settings.push(("describe_chunk_size", describe_chunk_size as f64));
+    }
+
     let aws_client = aws_sdk_ecs::client::Client::new(&aws_config);
-    let ecs_client = Arc::new(EcsClient::new(aws_client, &config.cluster_names));
+    let cluster_names: Vec<&str> = config
+        .clusters
+        .iter()
+        .map(|cluster| cluster.name.as_str())
+        .collect();
+    let mut ecs_client = EcsClient::new(aws_client, &cluster_names)
+        .with_cluster_discovery(config.all_clusters)
+        .with_cluster_tags(config.cluster_tags.clone())
+        .with_tag_labels(&config.tag_labels)
+        .with_service_include(config.service_include.clone())
+        .with_instance_include(config.instance_include.clone())
+        .with_images(config.with_images)
+        .with_task_definitions(config.with_task_definitions)
+        .with_disabled_collectors(config.disabled_collectors.clone())
+        .with_concurrency(config.max_concurrency, &config.cluster_concurrency)
+        .with_cluster_parallelism(config.cluster_parallelism)
+        .with_scrape_timeout(config.scrape_timeout)
+        .with_describe_chunk_size(config.describe_chunk_size)
+        .with_max_retries(config.max_retries)
+        .with_attribute_counts(config.counted_attributes.clone())
+        .with_managed_agents(config.with_managed_agents)
+        .with_tasks(config.with_tasks)
+        .with_stopped_tasks(config.max_stopped_tasks)
+        .with_cluster_label(&config.cluster_label)
+        .with_hide_empty_clusters(config.hide_empty_clusters)
+        .with_cluster_arn_label(config.emit_cluster_arn)
+        .with_instance_ip_label(config.instance_ip_label)
+        .with_capacity_shortfall(config.with_capacity_shortfall)
+        .with_omit_registered_resources(config.omit_registered_resources)
+        .with_service_name_filter(
+            config.service_name_include.clone(),
+            config.service_name_exclude.clone(),
+        );
+    if config
+        .clusters
+        .iter()
+        .any(|cluster| cluster.region.is_some())
+    {
+        ecs_client = ecs_client.with_region_label(region.as_ref());
+    }
+    for cluster in &config.clusters {
+        if cluster.role.is_none() && cluster.region.is_none() {
+            continue;
+        }
+        let cluster_region = cluster
+            .region
+            .clone()
+            .map(Region::new)
+            .unwrap_or_else(|| region.clone());
+        let cp = match &cluster.role {
+            Some(role) => SharedCredentialsProvider::new(get_credentials_provider(
+                base_credentials_provider.clone(),
+                role,
+                config.external_id.as_deref(),
+                config.session_name.as_deref(),
+                cluster_region.clone(),
+                config.sts_endpoint.as_deref(),
+            )),
+            // Without its own role, the cluster is queried with the exporter's credentials
+            None => aws_config
+                .credentials_provider()
+                .cloned()
+                .unwrap_or_else(|| base_credentials_provider.clone()),
+        };
+        let cluster_aws_config = aws_config::from_env()
+            .region(cluster_region.clone())
+            .timeout_config(timeout_config.clone())
+            .credentials_provider(cp)
+            .load()
+            .await;
+        ecs_client = ecs_client
+            .with_cluster_region(&cluster.name, cluster_region.as_ref())
+            .with_cluster_client(&cluster.name, aws_sdk_ecs::Client::new(&cluster_aws_config));
+    }
+    if let Some(metrics_prefix) = &config.metrics_prefix {
+        ecs_client = ecs_client.with_metrics_prefix(metrics_prefix);
+    }
+    if let Some(shared_concurrency) = config.shared_concurrency {
+        // Every regional client must be given this same semaphore
+        ecs_client =
+            ecs_client.with_shared_concurrency(Arc::new(Semaphore::new(shared_concurrency)));
+    }
+    if config.with_quotas {
+        ecs_client = ecs_client.with_quotas(aws_sdk_servicequotas::Client::new(&aws_config));
+    }
+    if config.with_target_health {
+        ecs_client =
+            ecs_client.with_target_health(aws_sdk_elasticloadbalancingv2::Client::new(&aws_config));
+    }
+    let mut scraper: Arc<dyn Scraper> = Arc::new(ecs_client);
 
-    let exporter = Exporter::new(
-        config.listen_address,
-        None,
-        ecs_client,
-        "aws_ecs_exporter",
-        &config.app_version,
-    );
-    exporter.work().await;
-
-    Ok(())
+    if let Some(namespace) = &config.cloudwatch_namespace {
+        let client = aws_sdk_cloudwatch::Client::new(&aws_config);
+        scraper = Arc::new(CloudWatchScraper::new(
+            scraper,
+            client,
+            namespace,
+            config
+                .metrics_prefix
+                .as_deref()
+                .unwrap_or(DEFAULT_METRICS_PREFIX),
+            &config.cluster_label,
+        ));
+    }
+
+    if let Some(ha_lock_config) = &config.ha_lock {
+        let owner = format!(
+            "{}-{}",
+            std::env::var("HOSTNAME").unwrap_or_default(),
+            std::process::id()
+        );
+        let lock = DynamoDbLock::new(
+            aws_sdk_dynamodb::Client::new(&aws_config),
+            &ha_lock_config.table_name,
+            &ha_lock_config.lock_name,
+            &owner,
+            ha_lock_config.lease,
+        );
+        scraper = Arc::new(LeaderScraper::spawn(
+            scraper,
+            Arc::new(lock),
+            ha_lock_config.lease,
+        ));
+    }
+
+    // Cached registries aren't pushed again, only actual scrapes are
+    if let Some(remote_write_config) = config.remote_write.clone().filter(|_| !config.once) {
+        scraper = Arc::new(RemoteWriteScraper::spawn(scraper, remote_write_config));
+    }
+
+    if let Some(cache_ttl) = config.cache_ttl {
+        scraper = Arc::new(CachingScraper::new(scraper, cache_ttl));
+    }
+
+    Ok((scraper, exporter_settings, role_credentials))
 }
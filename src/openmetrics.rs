@@ -0,0 +1,201 @@
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use prometheus::Encoder;
+use std::io::Write;
+
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Marks the end of an OpenMetrics exposition, which must be its last line
+pub const EOF: &[u8] = b"# EOF\n";
+
+/// Encodes metric families in the OpenMetrics text format, which the prometheus crate lacks
+pub struct OpenMetricsEncoder;
+
+impl OpenMetricsEncoder {
+    /// Encodes the families without the trailing `# EOF`, so that an exposition can be sent in parts
+    pub fn encode_families<W: Write>(
+        &self,
+        metric_families: &[MetricFamily],
+        writer: &mut W,
+    ) -> prometheus::Result<()> {
+        for metric_family in metric_families {
+            encode_family(metric_family, writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Encoder for OpenMetricsEncoder {
+    fn encode<W: Write>(
+        &self,
+        metric_families: &[MetricFamily],
+        writer: &mut W,
+    ) -> prometheus::Result<()> {
+        self.encode_families(metric_families, writer)?;
+        writer.write_all(EOF)?;
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        OPENMETRICS_FORMAT
+    }
+}
+
+fn encode_family<W: Write>(metric_family: &MetricFamily, writer: &mut W) -> prometheus::Result<()> {
+    let name = metric_family.get_name();
+    // Counter samples are suffixed with `_total`, which isn't part of the family name
+    let (name, type_name) = match metric_family.get_field_type() {
+        MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+        MetricType::GAUGE => (name, "gauge"),
+        MetricType::SUMMARY => (name, "summary"),
+        MetricType::HISTOGRAM => (name, "histogram"),
+        MetricType::UNTYPED => (name, "unknown"),
+    };
+    writeln!(writer, "# TYPE {} {}", name, type_name)?;
+    if !metric_family.get_help().is_empty() {
+        writeln!(
+            writer,
+            "# HELP {} {}",
+            name,
+            escape(metric_family.get_help())
+        )?;
+    }
+
+    for metric in metric_family.get_metric() {
+        let labels = metric.get_label();
+        match metric_family.get_field_type() {
+            MetricType::COUNTER => write_sample(
+                writer,
+                name,
+                "_total",
+                labels,
+                None,
+                metric.get_counter().get_value(),
+            )?,
+            MetricType::GAUGE => write_sample(
+                writer,
+                name,
+                "",
+                labels,
+                None,
+                metric.get_gauge().get_value(),
+            )?,
+            MetricType::UNTYPED => write_sample(
+                writer,
+                name,
+                "",
+                labels,
+                None,
+                metric.get_untyped().get_value(),
+            )?,
+            MetricType::HISTOGRAM => {
+                let histogram = metric.get_histogram();
+                let mut has_inf_bucket = false;
+                for bucket in histogram.get_bucket() {
+                    has_inf_bucket |= bucket.get_upper_bound() == f64::INFINITY;
+                    write_sample(
+                        writer,
+                        name,
+                        "_bucket",
+                        labels,
+                        Some(("le", bucket.get_upper_bound())),
+                        bucket.get_cumulative_count() as f64,
+                    )?;
+                }
+                if !has_inf_bucket {
+                    write_sample(
+                        writer,
+                        name,
+                        "_bucket",
+                        labels,
+                        Some(("le", f64::INFINITY)),
+                        histogram.get_sample_count() as f64,
+                    )?;
+                }
+                write_sample(
+                    writer,
+                    name,
+                    "_sum",
+                    labels,
+                    None,
+                    histogram.get_sample_sum(),
+                )?;
+                write_sample(
+                    writer,
+                    name,
+                    "_count",
+                    labels,
+                    None,
+                    histogram.get_sample_count() as f64,
+                )?;
+            }
+            MetricType::SUMMARY => {
+                let summary = metric.get_summary();
+                for quantile in summary.get_quantile() {
+                    write_sample(
+                        writer,
+                        name,
+                        "",
+                        labels,
+                        Some(("quantile", quantile.get_quantile())),
+                        quantile.get_value(),
+                    )?;
+                }
+                write_sample(writer, name, "_sum", labels, None, summary.get_sample_sum())?;
+                write_sample(
+                    writer,
+                    name,
+                    "_count",
+                    labels,
+                    None,
+                    summary.get_sample_count() as f64,
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_sample<W: Write>(
+    writer: &mut W,
+    name: &str,
+    suffix: &str,
+    labels: &[LabelPair],
+    extra_label: Option<(&str, f64)>,
+    value: f64,
+) -> prometheus::Result<()> {
+    write!(writer, "{}{}", name, suffix)?;
+    let extra_label = extra_label.map(|(name, value)| (name, format_value(value)));
+    let mut labels = labels
+        .iter()
+        .map(|label| (label.get_name(), escape(label.get_value())))
+        .chain(extra_label)
+        .peekable();
+    if labels.peek().is_some() {
+        let labels: Vec<String> = labels
+            .map(|(name, value)| format!("{}=\"{}\"", name, value))
+            .collect();
+        write!(writer, "{{{}}}", labels.join(","))?;
+    }
+    writeln!(writer, " {}", format_value(value))?;
+    Ok(())
+}
+
+fn format_value(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else if value.is_nan() {
+        "NaN".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Escapes label values and help texts
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('"', "\\\"")
+}
@@ -0,0 +1,275 @@
+use crate::exporter::Scraper;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use prometheus::proto::{MetricFamily, MetricType};
+use prometheus::Registry;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Unchanged series are still pushed this often, as Prometheus considers series without samples
+/// for 5 minutes to be stale
+const RESEND_INTERVAL: Duration = Duration::from_secs(240);
+
+#[derive(Clone, Debug)]
+pub struct RemoteWriteConfig {
+    pub url: String,
+    pub interval: Duration,
+    pub external_labels: Vec<(String, String)>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub changed_only: bool,
+}
+
+/// Hands the registry of every successful scrape over to the remote writer, so that pushing
+/// doesn't need scrapes of its own
+pub struct RemoteWriteScraper {
+    scraper: Arc<dyn Scraper>,
+    sender: watch::Sender<Option<Registry>>,
+}
+
+impl RemoteWriteScraper {
+    /// Wraps the scraper and starts pushing what it scrapes in the background
+    pub fn spawn(scraper: Arc<dyn Scraper>, config: RemoteWriteConfig) -> Self {
+        let (sender, receiver) = watch::channel(None);
+        tokio::spawn(RemoteWriter::new(config).run(scraper.clone(), receiver));
+        Self { scraper, sender }
+    }
+}
+
+#[async_trait]
+impl Scraper for RemoteWriteScraper {
+    async fn scrape(&self) -> Result<Registry> {
+        let registry = self.scraper.scrape().await?;
+        // Sending only fails once the writer stopped, in which case there's nothing left to do
+        let _ = self.sender.send(Some(registry.clone()));
+        Ok(registry)
+    }
+
+    fn is_standby(&self) -> bool {
+        self.scraper.is_standby()
+    }
+}
+
+/// Pushes the exporter's metrics to a Prometheus remote write endpoint
+struct RemoteWriter {
+    client: reqwest::Client,
+    config: RemoteWriteConfig,
+    // Last value sent for each series of the latest push and when, used when only changed
+    // values should be pushed
+    last_sent: HashMap<Vec<Label>, (f64, Instant)>,
+}
+
+impl RemoteWriter {
+    fn new(config: RemoteWriteConfig) -> Self {
+        Self {
+            // A hung endpoint would otherwise hold up every following push
+            client: reqwest::Client::builder()
+                .timeout(config.interval)
+                .build()
+                .expect("Failed to build the remote write client"),
+            config,
+            last_sent: HashMap::new(),
+        }
+    }
+
+    /// Pushes the metrics of every scrape, as long as the scraper is around
+    ///
+    /// If nothing was scraped for an interval, e.g. because Prometheus doesn't scrape the
+    /// exporter, it scrapes by itself. Failures are logged and the next push is attempted anyway.
+    async fn run(
+        mut self,
+        scraper: Arc<dyn Scraper>,
+        mut scrapes: watch::Receiver<Option<Registry>>,
+    ) {
+        info!(url = %self.config.url, "Starting remote write");
+        loop {
+            let registry = match tokio::time::timeout(self.config.interval, scrapes.changed()).await
+            {
+                Ok(Ok(())) => scrapes.borrow().clone(),
+                Ok(Err(_)) => return,
+                Err(_) => match scraper.scrape().await {
+                    Ok(registry) => Some(registry),
+                    Err(err) => {
+                        warn!("Scrape for remote write failed: {}", err);
+                        None
+                    }
+                },
+            };
+            let registry = match registry {
+                Some(registry) => registry,
+                None => continue,
+            };
+            let mut metric_families = prometheus::gather();
+            metric_families.extend(registry.gather());
+            if let Err(err) = self.push(&metric_families).await {
+                warn!("Failed to push metrics to remote write endpoint: {}", err);
+            }
+        }
+    }
+
+    async fn push(&mut self, metric_families: &[MetricFamily]) -> Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        let now = Instant::now();
+        let mut timeseries = vec![];
+        // Only the series of this push are kept, so that those which disappeared are forgotten
+        let mut last_sent = HashMap::new();
+        for (mut labels, value) in flatten(metric_families) {
+            labels.extend(
+                self.config
+                    .external_labels
+                    .iter()
+                    .map(|(name, value)| Label {
+                        name: name.clone(),
+                        value: value.clone(),
+                    }),
+            );
+            labels.sort_by(|a, b| a.name.cmp(&b.name));
+            if self.config.changed_only {
+                if let Some(&(sent_value, sent_at)) = self.last_sent.get(&labels) {
+                    if sent_value == value && now.duration_since(sent_at) < RESEND_INTERVAL {
+                        last_sent.insert(labels, (sent_value, sent_at));
+                        continue;
+                    }
+                }
+                last_sent.insert(labels.clone(), (value, now));
+            }
+            timeseries.push(TimeSeries {
+                labels,
+                samples: vec![Sample { value, timestamp }],
+            });
+        }
+        self.last_sent = last_sent;
+        if timeseries.is_empty() {
+            return Ok(());
+        }
+
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&prost::Message::encode_to_vec(&WriteRequest { timeseries }))?;
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .header("Content-Encoding", "snappy")
+            .header("Content-Type", "application/x-protobuf")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body);
+        if let Some(username) = &self.config.username {
+            request = request.basic_auth(username, self.config.password.as_ref());
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(eyre!(
+                "remote write endpoint returned {}",
+                response.status()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Converts the metric families to individual series, expanding histograms and summaries
+fn flatten(metric_families: &[MetricFamily]) -> Vec<(Vec<Label>, f64)> {
+    let mut result = vec![];
+    for family in metric_families {
+        let name = family.get_name();
+        for metric in family.get_metric() {
+            let labels: Vec<Label> = metric
+                .get_label()
+                .iter()
+                .map(|pair| Label {
+                    name: pair.get_name().to_string(),
+                    value: pair.get_value().to_string(),
+                })
+                .collect();
+            let series = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+                let mut labels = labels.clone();
+                labels.push(Label {
+                    name: "__name__".to_string(),
+                    value: format!("{}{}", name, suffix),
+                });
+                if let Some((name, value)) = extra {
+                    labels.push(Label {
+                        name: name.to_string(),
+                        value,
+                    });
+                }
+                (labels, value)
+            };
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    result.push(series("", None, metric.get_counter().get_value()))
+                }
+                MetricType::GAUGE => result.push(series("", None, metric.get_gauge().get_value())),
+                MetricType::UNTYPED => {
+                    result.push(series("", None, metric.get_untyped().get_value()))
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    for bucket in histogram.get_bucket() {
+                        result.push(series(
+                            "_bucket",
+                            Some(("le", bucket.get_upper_bound().to_string())),
+                            bucket.get_cumulative_count() as f64,
+                        ));
+                    }
+                    result.push(series(
+                        "_bucket",
+                        Some(("le", "+Inf".to_string())),
+                        histogram.get_sample_count() as f64,
+                    ));
+                    result.push(series("_sum", None, histogram.get_sample_sum()));
+                    result.push(series("_count", None, histogram.get_sample_count() as f64));
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        result.push(series(
+                            "",
+                            Some(("quantile", quantile.get_quantile().to_string())),
+                            quantile.get_value(),
+                        ));
+                    }
+                    result.push(series("_sum", None, summary.get_sample_sum()));
+                    result.push(series("_count", None, summary.get_sample_count() as f64));
+                }
+            }
+        }
+    }
+    result
+}
+
+// Messages from the remote write protocol, see
+// https://github.com/prometheus/prometheus/blob/main/prompb/remote.proto
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, prost::Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
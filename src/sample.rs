@@ -0,0 +1,146 @@
+//! Synthetic scraper used to load test the exporter's HTTP path without a large AWS account
+//!
+//! Only built with the `bench` feature. It measures encoding, compressing and serving the
+//! metrics, not scraping them: `EcsClient` isn't involved, so neither the describe calls nor their
+//! concurrency and retries are exercised.
+
+use crate::exporter::Scraper;
+use async_trait::async_trait;
+use clap::Arg;
+use color_eyre::Result;
+use prometheus::{opts, register_int_gauge_vec_with_registry, Registry};
+
+const RESOURCES: [&str; 2] = ["cpu", "ram"];
+
+#[derive(Debug)]
+pub struct SampleConfig {
+    pub clusters: usize,
+    pub services: usize,
+    pub instances: usize,
+}
+
+/// Hidden arguments configuring the synthesized clusters
+pub fn args<'a>() -> [Arg<'a>; 3] {
+    [
+        Arg::new("sample_clusters")
+            .long("sample-clusters")
+            .takes_value(true)
+            .value_name("COUNT")
+            .required(false)
+            .hide(true)
+            .validator(|value| value.parse::<usize>().map(|_| ()))
+            .help("Serve synthesized metrics for this many clusters instead of querying AWS, to load test the HTTP path only"),
+        Arg::new("sample_services")
+            .long("sample-services")
+            .takes_value(true)
+            .value_name("COUNT")
+            .required(false)
+            .hide(true)
+            .default_value("10")
+            .validator(|value| value.parse::<usize>().map(|_| ()))
+            .help("Number of services in each synthesized cluster"),
+        Arg::new("sample_instances")
+            .long("sample-instances")
+            .takes_value(true)
+            .value_name("COUNT")
+            .required(false)
+            .hide(true)
+            .default_value("10")
+            .validator(|value| value.parse::<usize>().map(|_| ()))
+            .help("Number of container instances in each synthesized cluster"),
+    ]
+}
+
+/// Produces a few of the main metric families of `EcsClient`, with made up values
+///
+/// Their names and labels are fixed, ignoring `--metrics-prefix` and `--cluster-label-name`.
+pub struct HttpLoadScraper {
+    config: SampleConfig,
+}
+
+impl HttpLoadScraper {
+    pub fn new(config: SampleConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait]
+impl Scraper for HttpLoadScraper {
+    async fn scrape(&self) -> Result<Registry> {
+        let registry = Registry::new();
+
+        let scrape_metric = register_int_gauge_vec_with_registry!(
+            opts!(
+                "aws_ecs_cluster_scrape_success",
+                "Whether the scrape of the cluster's resources was successful"
+            ),
+            &["cluster_name", "scraped_resource"],
+            registry
+        )?;
+        let service_desired = register_int_gauge_vec_with_registry!(
+            opts!("aws_ecs_service_desired", "Desired Number of ECS Services"),
+            &["cluster_name", "service_name", "launch_type"],
+            registry
+        )?;
+        let service_current = register_int_gauge_vec_with_registry!(
+            opts!(
+                "aws_ecs_service_current_total",
+                "Current Number of ECS Services"
+            ),
+            &["cluster_name", "service_name", "state", "launch_type"],
+            registry
+        )?;
+        let resources_registered = register_int_gauge_vec_with_registry!(
+            opts!(
+                "aws_ecs_instance_resources_registered",
+                "Initial resources available on ECS Container Instance"
+            ),
+            &["cluster_name", "ec2_instance_id", "instance_id", "resource"],
+            registry
+        )?;
+        let resources_remaining = register_int_gauge_vec_with_registry!(
+            opts!(
+                "aws_ecs_instance_resources_remaining",
+                "Initial resources available on ECS Container Instance"
+            ),
+            &["cluster_name", "ec2_instance_id", "instance_id", "resource"],
+            registry
+        )?;
+
+        for cluster in 0..self.config.clusters {
+            let cluster_name = format!("sample-cluster-{}", cluster);
+            for scraped_resource in ["cluster_instances", "services"] {
+                scrape_metric
+                    .with_label_values(&[&cluster_name, scraped_resource])
+                    .set(1);
+            }
+
+            for service in 0..self.config.services {
+                let service_name = format!("sample-service-{}", service);
+                let desired = (service % 5 + 1) as i64;
+                service_desired
+                    .with_label_values(&[&cluster_name, &service_name, "EC2"])
+                    .set(desired);
+                for (state, value) in [("running", desired), ("pending", 0)] {
+                    service_current
+                        .with_label_values(&[&cluster_name, &service_name, state, "EC2"])
+                        .set(value);
+                }
+            }
+
+            for instance in 0..self.config.instances {
+                let instance_id = format!("i-{:08x}{:09x}", cluster, instance);
+                for resource in RESOURCES {
+                    resources_registered
+                        .with_label_values(&[&cluster_name, &instance_id, &instance_id, resource])
+                        .set(4096);
+                    resources_remaining
+                        .with_label_values(&[&cluster_name, &instance_id, &instance_id, resource])
+                        .set((instance % 4 * 1024) as i64);
+                }
+            }
+        }
+
+        Ok(registry)
+    }
+}